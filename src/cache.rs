@@ -0,0 +1,276 @@
+//! Cache of scored recommendation results.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::collections::{BTreeMap,HashMap};
+use std::mem;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use recommendation::{Etext,Recommendation,Score,select_top};
+
+/// Cache key: recommender name, etext number, and a description of
+/// any options affecting the results.
+type Key = (String,Etext,String);
+
+type Results = Arc<Vec<(Etext,Score)>>;
+
+/// A bounded, thread-safe, least-recently-used cache of scored
+/// results, limited both in number of entries and in bytes.
+pub struct Cache {
+    capacity : usize,
+    budget   : usize,
+    lru      : Mutex<Lru>,
+    hits     : AtomicUsize,
+    misses   : AtomicUsize,
+}
+
+/// Cache contents.
+struct Lru {
+    /// Results and the time they were last used.
+    entries : HashMap<Key,(Results,u64)>,
+    /// Keys by the time they were last used.
+    order   : BTreeMap<u64,Key>,
+    /// Clock for recording uses.
+    tick    : u64,
+    /// Approximate size of the cached results.
+    bytes   : usize,
+}
+
+/// Cache statistics.
+#[derive(RustcEncodable)]
+pub struct CacheStats {
+    pub hits     : usize,
+    pub misses   : usize,
+    pub entries  : usize,
+    pub bytes    : usize,
+    pub capacity : usize,
+    pub budget   : usize,
+}
+
+impl Cache {
+
+    /// Create a cache holding at most `capacity` results, of at most
+    /// `budget` bytes in total. A capacity of 0 disables caching.
+    pub fn new(capacity : usize, budget : usize) -> Cache {
+        Cache {
+            capacity : capacity,
+            budget   : budget,
+            lru      : Mutex::new(Lru {
+                entries : HashMap::new(),
+                order   : BTreeMap::new(),
+                tick    : 0,
+                bytes   : 0,
+            }),
+            hits     : AtomicUsize::new(0),
+            misses   : AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the cached results for `key`, or compute and cache them.
+    /// Missing results are not cached.
+    fn get_or_insert<F>(&self, key : Key, compute : F) -> Option<Results>
+        where F : FnOnce() -> Option<Vec<(Etext,Score)>> {
+        if let Some(results) = self.lock().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(results);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // The lock is not held while computing; concurrent misses on
+        // the same key will each compute the results.
+        compute().map( |results| {
+            let results = Arc::new(results);
+            if self.capacity > 0 {
+                self.lock().insert(key, results.clone(), self.capacity, self.budget);
+            }
+            results
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lock();
+        CacheStats {
+            hits     : self.hits.load(Ordering::Relaxed),
+            misses   : self.misses.load(Ordering::Relaxed),
+            entries  : lru.entries.len(),
+            bytes    : lru.bytes,
+            capacity : self.capacity,
+            budget   : self.budget,
+        }
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<Lru> {
+        // A panic while holding the lock cannot leave the cache
+        // inconsistent in a way that matters; carry on.
+        match self.lru.lock() {
+            Ok(guard)     => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Lru {
+
+    fn get(&mut self, key : &Key) -> Option<Results> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(&mut (ref results, ref mut used)) => {
+                self.order.remove(used);
+                *used = tick;
+                self.order.insert(tick, key.clone());
+                Some(results.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key : Key, results : Results, capacity : usize, budget : usize) {
+        let size = size_of(&results);
+        if size > budget { return; }
+        self.remove(&key);
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (results, self.tick));
+        self.bytes += size;
+        // Evict the least recently used results until within bounds.
+        while self.entries.len() > capacity || self.bytes > budget {
+            let oldest = match self.order.keys().next() {
+                Some(&tick) => tick,
+                None        => break,
+            };
+            let key = self.order[&oldest].clone();
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key : &Key) {
+        if let Some((results, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.bytes -= size_of(&results);
+        }
+    }
+}
+
+/// Approximate size in bytes of a cached result.
+fn size_of(results : &Results) -> usize {
+    results.len() * mem::size_of::<(Etext,Score)>() + mem::size_of::<Vec<(Etext,Score)>>()
+}
+
+/// A recommendation source whose results are cached.
+pub struct Cached<'a> {
+    name           : &'static str,
+    options        : String,
+    recommendation : &'a Recommendation,
+    cache          : &'a Cache,
+}
+
+impl<'a> Cached<'a> {
+
+    /// Cache the results of `recommendation` under `name`. `options`
+    /// describes any settings of the recommendation that affect its
+    /// results, so that differently-configured recommendations do
+    /// not share entries.
+    pub fn new(name : &'static str, options : String, recommendation : &'a Recommendation, cache : &'a Cache) -> Cached<'a> {
+        Cached { name : name, options : options, recommendation : recommendation, cache : cache }
+    }
+
+    fn results(&self, etext_no : Etext) -> Option<Results> {
+        let key = (self.name.to_string(), etext_no, self.options.clone());
+        self.cache.get_or_insert(key, || self.recommendation.scored_results(etext_no))
+    }
+}
+
+impl<'a> Recommendation for Cached<'a> {
+
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.results(etext_no).map( |results| (*results).clone() )
+    }
+
+    /// Select from the cached results without copying them.
+    fn top_k(&self, etext_no : Etext, k : usize, offset : usize) -> Option<(usize,Vec<(Etext,Score)>)> {
+        self.results(etext_no).map( |results| (results.len(), select_top(&results, k, offset)) )
+    }
+}
+
+#[cfg(test)]
+struct Counting(AtomicUsize);
+
+#[cfg(test)]
+impl Recommendation for Counting {
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        if etext_no == 0 { None } else { Some(vec![(etext_no, 0.0)]) }
+    }
+}
+
+#[test]
+fn test_hits() {
+    let cache = Cache::new(10, 1 << 20);
+    let source = Counting(AtomicUsize::new(0));
+    let cached = Cached::new("counting", String::new(), &source, &cache);
+    assert_eq!(cached.scored_results(1), Some(vec![(1, 0.0)]));
+    assert_eq!(cached.scored_results(1), Some(vec![(1, 0.0)]));
+    assert_eq!(cached.top_k(1, 5, 0), Some((1, vec![(1, 0.0)])));
+    assert_eq!(cached.scored_results(0), None);
+    assert_eq!(cached.scored_results(0), None);
+    assert_eq!(source.0.load(Ordering::Relaxed), 3);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 1));
+}
+
+#[test]
+fn test_options() {
+    let cache = Cache::new(10, 1 << 20);
+    let source = Counting(AtomicUsize::new(0));
+    Cached::new("counting", "a".to_string(), &source, &cache).scored_results(1);
+    Cached::new("counting", "b".to_string(), &source, &cache).scored_results(1);
+    assert_eq!(source.0.load(Ordering::Relaxed), 2);
+}
+
+#[test]
+fn test_eviction() {
+    let cache = Cache::new(2, 1 << 20);
+    let source = Counting(AtomicUsize::new(0));
+    let cached = Cached::new("counting", String::new(), &source, &cache);
+    cached.scored_results(1);
+    cached.scored_results(2);
+    // 1 is now more recently used than 2.
+    cached.scored_results(1);
+    cached.scored_results(3);
+    assert_eq!(source.0.load(Ordering::Relaxed), 3);
+    cached.scored_results(1);
+    assert_eq!(source.0.load(Ordering::Relaxed), 3);
+    cached.scored_results(2);
+    assert_eq!(source.0.load(Ordering::Relaxed), 4);
+    assert_eq!(cache.stats().entries, 2);
+}
+
+#[test]
+fn test_budget() {
+    let entry = mem::size_of::<(Etext,Score)>() + mem::size_of::<Vec<(Etext,Score)>>();
+    let cache = Cache::new(10, 2 * entry);
+    let source = Counting(AtomicUsize::new(0));
+    let cached = Cached::new("counting", String::new(), &source, &cache);
+    for etext_no in 1..5 { cached.scored_results(etext_no); }
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.bytes), (2, 2 * entry));
+}
@@ -1,4 +1,4 @@
-//! Combined style and topic recommendations.
+//! Combined recommendations from several weighted sources.
 
 /*
  * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
@@ -20,20 +20,255 @@
  * 02110-1301 USA.
  */
 
+use std::str::FromStr;
+
 use recommendation::{Etext,Recommendation,Score};
 
+/// Rank offset used by reciprocal rank fusion; the conventional value
+/// from Cormack et al.
+const RRF_K : Score = 60.0;
+
+/// Method used to combine the scores `s_i` given to each etext by the
+/// sources, with weights `w_i`. In every mode, lower combined scores
+/// are better.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Mode {
+    /// `s_1^w_1 * s_2^w_2 * ...`.
+    Product,
+    /// `w_1 * s_1 + w_2 * s_2 + ...`, after normalization.
+    Sum,
+    /// `1 / (w_1 / (k + rank_1) + w_2 / (k + rank_2) + ...)`.
+    ReciprocalRank,
+    /// The best of the normalized scores, each shifted down by
+    /// `ln w_i` so that heavier sources are more likely to be chosen.
+    /// Every weight must be positive.
+    Min,
+    /// The worst of the normalized scores, each shifted up by
+    /// `ln w_i` so that heavier sources are more likely to be chosen.
+    /// Every weight must be positive.
+    Max,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+    fn from_str(s : &str) -> Result<Mode,()> {
+        match s {
+            "product" => Ok(Mode::Product),
+            "sum"     => Ok(Mode::Sum),
+            "rrf"     => Ok(Mode::ReciprocalRank),
+            "min"     => Ok(Mode::Min),
+            "max"     => Ok(Mode::Max),
+            _         => Err(()),
+        }
+    }
+}
+
+/// Rescaling applied to each list of scores before the `Sum`, `Min`
+/// and `Max` modes combine them.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Normalization {
+    /// Scale scores to [0,1].
+    MinMax,
+    /// Scale scores to zero mean and unit standard deviation.
+    ZScore,
+}
+
+impl FromStr for Normalization {
+    type Err = ();
+    fn from_str(s : &str) -> Result<Normalization,()> {
+        match s {
+            "minmax" => Ok(Normalization::MinMax),
+            "zscore" => Ok(Normalization::ZScore),
+            _        => Err(()),
+        }
+    }
+}
+
+/// Treatment of a candidate etext missing from some sources.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Missing {
+    /// Leave the candidate out of the combined results.
+    Skip,
+    /// Use the median of the source's scores.
+    Median,
+    /// Use the source's worst (highest) score.
+    Worst,
+}
+
+impl FromStr for Missing {
+    type Err = ();
+    fn from_str(s : &str) -> Result<Missing,()> {
+        match s {
+            "skip"   => Ok(Missing::Skip),
+            "median" => Ok(Missing::Median),
+            "worst"  => Ok(Missing::Worst),
+            _        => Err(()),
+        }
+    }
+}
+
+/// Score fusion settings.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Fusion {
+    pub mode          : Mode,
+    pub normalization : Normalization,
+    /// Treatment of candidates missing from some sources.
+    pub missing       : Missing,
+    /// If the query etext is missing from some sources, combine the
+    /// remaining sources rather than failing.
+    pub fallback      : bool,
+}
+
+impl Default for Fusion {
+    /// The product of the scores of etexts present in every source.
+    fn default() -> Fusion {
+        Fusion {
+            mode          : Mode::Product,
+            normalization : Normalization::MinMax,
+            missing       : Missing::Skip,
+            fallback      : false,
+        }
+    }
+}
+
+/// Set of the sources contributing a score to a combined result; bit
+/// `i` is set if source `i` did.
+pub type Contributors = u64;
+
+/// One weighted source of recommendations in a combination.
+pub struct Source<'a> {
+    /// Name of the source, such as "style".
+    pub name           : &'static str,
+    pub recommendation : &'a Recommendation,
+    pub weight         : Score,
+}
+
 /// Combined recommendations data
 pub struct Combination<'a> {
-    left : &'a Recommendation,
-    right : &'a Recommendation,
+    sources : Vec<Source<'a>>,
+    fusion : Fusion,
 }
 
 impl<'a> Combination<'a> {
 
-    /// Construct a new Combination structure based on existing
-    /// `Style` and `Type` objects.
-    pub fn new(left : &'a Recommendation, right : &'a Recommendation) -> Combination<'a> {
-        Combination { left : left, right : right }
+    /// Construct a new Combination structure with no sources,
+    /// combining their scores as described by `fusion`.
+    pub fn new(fusion : Fusion) -> Combination<'a> {
+        Combination { sources : Vec::new(), fusion : fusion }
+    }
+
+    /// Add a source of recommendations, such as a `Style` or `Topic`
+    /// object, with the given weight.
+    ///
+    /// # Panics
+    ///
+    /// There can be at most 64 sources.
+    pub fn add(mut self, name : &'static str, recommendation : &'a Recommendation, weight : Score) -> Combination<'a> {
+        assert!(self.sources.len() < 64);
+        self.sources.push(Source { name : name, recommendation : recommendation, weight : weight });
+        self
+    }
+
+    /// The sources being combined.
+    pub fn sources(&self) -> &[Source<'a>] {
+        &self.sources
+    }
+
+    /// The fusion settings.
+    pub fn fusion(&self) -> &Fusion {
+        &self.fusion
+    }
+
+    /// The names of the sources in a set of contributors.
+    pub fn names(&self, contributors : Contributors) -> Vec<&'static str> {
+        self.sources.iter().enumerate()
+            .filter( |&(i,_)| contributors & (1 << i) != 0 )
+            .map( |(_,s)| s.name )
+            .collect()
+    }
+
+    /// Return a vector of (etext number, score, contributors)
+    /// triples if possible, sorted by etext number, where the
+    /// contributors are the sources which had a score for the etext.
+    ///
+    /// # Failures
+    ///
+    /// Returns None if there are no sources, or if the supplied etext
+    /// number is not valid for some source and `fallback` is not set,
+    /// or for every source.
+    ///
+    /// Etexts missing from some sources' recommendation lists are
+    /// skipped or given imputed scores as directed by `missing`.
+    pub fn combined_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score,Contributors)>> {
+        let mut lists = Vec::with_capacity(self.sources.len());
+        let mut weights = Vec::with_capacity(self.sources.len());
+        let mut indices = Vec::with_capacity(self.sources.len());
+        for (i, source) in self.sources.iter().enumerate() {
+            match source.recommendation.scored_results(etext_no) {
+                Some(list) => {
+                    lists.push(list);
+                    weights.push(source.weight);
+                    indices.push(i);
+                }
+                None if self.fusion.fallback => { }
+                None                         => return None,
+            }
+        }
+        if lists.is_empty() { return None; }
+
+        // Join the lists on etext number. Each list is sorted by
+        // etext number.
+        let mut etexts = Vec::with_capacity(lists[0].len());
+        let mut contributors = Vec::with_capacity(lists[0].len());
+        let mut columns : Vec<Vec<Option<Score>>> =
+            lists.iter().map( |_| Vec::with_capacity(lists[0].len()) ).collect();
+        let mut positions = vec![0; lists.len()];
+        loop {
+            // The smallest etext number at the head of any list.
+            let target = lists.iter().zip(positions.iter())
+                .filter( |&(list, &pos)| pos < list.len() )
+                .map( |(list, &pos)| list[pos].0 )
+                .min();
+            let target = match target {
+                Some(target) => target,
+                None         => break,
+            };
+            let mut present : Contributors = 0;
+            for (((list, pos), column), &i) in lists.iter().zip(positions.iter_mut())
+                .zip(columns.iter_mut()).zip(indices.iter()) {
+                if *pos < list.len() && list[*pos].0 == target {
+                    column.push(Some(list[*pos].1));
+                    present |= 1 << i;
+                    *pos += 1;
+                } else {
+                    column.push(None);
+                }
+            }
+            etexts.push(target);
+            contributors.push(present);
+        }
+
+        // Fill in or remove the missing scores.
+        let every : Contributors = indices.iter().fold(0, |a,&i| a | (1 << i));
+        let keep : Vec<bool> = contributors.iter()
+            .map( |&c| c == every || self.fusion.missing != Missing::Skip )
+            .collect();
+        let columns : Vec<Vec<Score>> = columns.iter().zip(lists.iter())
+            .map( |(column, list)| {
+                let imputed = impute(list, self.fusion.missing);
+                column.iter().zip(keep.iter())
+                    .filter( |&(_, &k)| k )
+                    .map( |(s, _)| s.unwrap_or(imputed) )
+                    .collect()
+            })
+            .collect();
+
+        let scores = self.fusion.combine(&columns, &weights);
+        Some(etexts.into_iter().zip(contributors.into_iter()).zip(keep.into_iter())
+             .filter( |&(_, k)| k )
+             .zip(scores.into_iter())
+             .map( |(((e, c), _), s)| (e, s, c) )
+             .collect())
     }
 
 }
@@ -47,15 +282,14 @@ impl<'a> Recommendation for Combination<'a> {
     ///
     /// # Failures
     ///
-    /// Returns None if the supplied etext number is not valid.
-    ///
-    /// If an etext is not in both recommendation lists, it will be
-    /// skipped in the combined list.
+    /// As for `combined_results`.
     ///
     /// # Examples
     ///
     /// ```
-    /// let combination = Combination::new(style,topic);
+    /// let combination = Combination::new(Fusion::default())
+    ///     .add("style", style, 1.0)
+    ///     .add("topic", topic, 1.0);
     /// let results = combination.scored_results(773);
     /// ```
     ///
@@ -63,35 +297,188 @@ impl<'a> Recommendation for Combination<'a> {
     /// with etext number 773, Oscar Wilde's *Lord Arthur Savile's
     /// Crime and Other Stories*.
     fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
-        match (self.left.scored_results(etext_no), self.right.scored_results(etext_no)) {
-            (Some(left), Some(right)) => {
-
-                let mut results = Vec::with_capacity(left.len());
-
-                let mut lefts = left.iter();
-                let mut next_left = lefts.next();
-                let mut rights = right.iter();
-                let mut next_right = rights.next();
-
-                loop {
-                    match (next_left, next_right) {
-                        (Some(&(sn,ss)), Some(&(tn,ts))) => {
-                            if      sn < tn { next_left = lefts.next(); }
-                            else if tn < sn { next_right = rights.next(); }
-                            else {
-                                results.push((sn, ss * ts));
-                                next_left = lefts.next();
-                                next_right = rights.next();
-                            }
-                        }
-                        _ => break
-                    }
+        self.combined_results(etext_no)
+            .map( |results| results.into_iter().map( |(e,s,_)| (e,s) ).collect() )
+    }
+
+}
+
+/// The score used for etexts missing from a source's list.
+fn impute(list : &[(Etext,Score)], missing : Missing) -> Score {
+    match missing {
+        Missing::Skip   => Score::NAN,
+        Missing::Worst  => list.iter().fold(Score::NEG_INFINITY, |a,&(_,s)| Score::max(a, s)),
+        Missing::Median => {
+            let mut scores : Vec<Score> = list.iter().map( |&(_,s)| s ).collect();
+            scores.sort_by( |l,r| panic_unless!("imputed scores", option: l.partial_cmp(r)) );
+            let mid = scores.len() / 2;
+            if scores.len() % 2 == 0 { (scores[mid - 1] + scores[mid]) / 2.0 } else { scores[mid] }
+        }
+    }
+}
+
+impl Fusion {
+
+    /// Combine columns of scores, one for each source, into a single
+    /// column of scores.
+    fn combine(&self, columns : &[Vec<Score>], weights : &[Score]) -> Vec<Score> {
+        let columns : Vec<Vec<Score>> = columns.iter()
+            .map( |column| {
+                match self.mode {
+                    Mode::Product        => column.clone(),
+                    Mode::ReciprocalRank => ranks(column),
+                    _                    => normalize(column, self.normalization),
                 }
-                Some(results)
-            }
-            (_,_) => None
+            })
+            .collect();
+        let rows = columns.first().map( |c| c.len() ).unwrap_or(0);
+        (0..rows)
+            .map( |i| {
+                let weighted = columns.iter().zip(weights.iter()).map( |(c,&w)| (c[i], w) );
+                match self.mode {
+                    Mode::Product        => weighted.fold(1.0, |a,(s,w)| a * s.powf(w)),
+                    Mode::Sum            => weighted.fold(0.0, |a,(s,w)| a + w * s),
+                    Mode::ReciprocalRank => 1.0 / weighted.fold(0.0, |a,(r,w)| a + w / (RRF_K + r)),
+                    Mode::Min            => weighted.fold(Score::INFINITY, |a,(s,w)| Score::min(a, s - w.ln())),
+                    Mode::Max            => weighted.fold(Score::NEG_INFINITY, |a,(s,w)| Score::max(a, s + w.ln())),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Rescale the scores.
+fn normalize(scores : &[Score], normalization : Normalization) -> Vec<Score> {
+    if scores.is_empty() { return Vec::new(); }
+    match normalization {
+        Normalization::MinMax => {
+            let min = scores.iter().cloned().fold(Score::INFINITY, Score::min);
+            let max = scores.iter().cloned().fold(Score::NEG_INFINITY, Score::max);
+            let range = max - min;
+            scores.iter().map( |&s| if range > 0.0 { (s - min) / range } else { 0.0 } ).collect()
         }
+        Normalization::ZScore => {
+            let n = scores.len() as Score;
+            let mean = scores.iter().fold(0.0, |a,&s| a + s) / n;
+            let sd = (scores.iter().fold(0.0, |a,&s| a + (s - mean) * (s - mean)) / n).sqrt();
+            scores.iter().map( |&s| if sd > 0.0 { (s - mean) / sd } else { 0.0 } ).collect()
+        }
+    }
+}
+
+/// Replace each score with its rank, starting at 1 for the best
+/// (lowest) score. Ties are ranked in their original order.
+fn ranks(scores : &[Score]) -> Vec<Score> {
+    let mut order : Vec<usize> = (0..scores.len()).collect();
+    order.sort_by( |&l,&r| panic_unless!("fusion scores", option: scores[l].partial_cmp(&scores[r])) );
+    let mut ranks = vec![0.0; scores.len()];
+    for (rank, &i) in order.iter().enumerate() {
+        ranks[i] = (rank + 1) as Score;
     }
+    ranks
+}
+
+#[cfg(test)]
+use recommendation::Fixed;
+
+#[cfg(test)]
+fn pair<'a>(mode : Mode, left : &'a Fixed, wl : Score, right : &'a Fixed, wr : Score) -> Combination<'a> {
+    Combination::new(Fusion { mode : mode, ..Fusion::default() })
+        .add("left", left, wl)
+        .add("right", right, wr)
+}
+
+#[test]
+fn test_product() {
+    let left = Fixed(vec![(1, 0.5), (2, 1.0), (3, 2.0)]);
+    let right = Fixed(vec![(1, 0.2), (3, 0.5), (4, 1.0)]);
+    let results = pair(Mode::Product, &left, 1.0, &right, 1.0).scored_results(1).unwrap();
+    assert_eq!(results, vec![(1, 0.1), (3, 1.0)]);
+}
+
+#[test]
+fn test_weighted_sum() {
+    let left = Fixed(vec![(1, 0.0), (2, 5.0), (3, 10.0)]);
+    let right = Fixed(vec![(1, 0.0), (2, 1.0), (3, 0.5)]);
+    let results = pair(Mode::Sum, &left, 0.5, &right, 1.0).scored_results(1).unwrap();
+    assert_eq!(results, vec![(1, 0.0), (2, 1.25), (3, 1.0)]);
+}
+
+#[test]
+fn test_rrf() {
+    let left = Fixed(vec![(1, 0.0), (2, 0.3), (3, 0.2)]);
+    let right = Fixed(vec![(1, 0.0), (2, 0.1), (3, 0.9)]);
+    let results = pair(Mode::ReciprocalRank, &left, 1.0, &right, 1.0).scored_results(1).unwrap();
+    assert_eq!(results[0].1, 1.0 / (2.0 / 61.0));
+    // 2 and 3 are ranked 2nd and 3rd by one score each.
+    assert_eq!(results[1].1, results[2].1);
+}
+
+#[test]
+fn test_min_max() {
+    let left = Fixed(vec![(1, 0.0), (2, 1.0), (3, 2.0)]);
+    let right = Fixed(vec![(1, 4.0), (2, 2.0), (3, 0.0)]);
+    let min = pair(Mode::Min, &left, 1.0, &right, 1.0).scored_results(1).unwrap();
+    assert_eq!(min, vec![(1, 0.0), (2, 0.5), (3, 0.0)]);
+    let max = pair(Mode::Max, &left, 1.0, &right, 1.0).scored_results(1).unwrap();
+    assert_eq!(max, vec![(1, 1.0), (2, 0.5), (3, 1.0)]);
+    // A heavier source is chosen more often, whatever the sign of
+    // its scores.
+    let heavy = pair(Mode::Min, &left, 1.0, &right, 2.0).scored_results(1).unwrap();
+    assert_eq!(heavy[1].1, 0.5 - (2.0 as Score).ln());
+    let heavy = pair(Mode::Max, &left, 2.0, &right, 1.0).scored_results(1).unwrap();
+    assert_eq!(heavy[1].1, 0.5 + (2.0 as Score).ln());
+}
+
+#[cfg(test)]
+struct Absent;
 
+#[cfg(test)]
+impl Recommendation for Absent {
+    fn scored_results(&self, _ : Etext) -> Option<Vec<(Etext,Score)>> { None }
 }
 
+#[test]
+fn test_missing() {
+    let left = Fixed(vec![(1, 1.0), (2, 2.0), (3, 4.0)]);
+    let right = Fixed(vec![(1, 1.0), (3, 3.0), (4, 2.0)]);
+    let skip = pair(Mode::Product, &left, 1.0, &right, 1.0);
+    assert_eq!(skip.combined_results(1).unwrap(), vec![(1, 1.0, 3), (3, 12.0, 3)]);
+    let worst = Combination::new(Fusion { missing : Missing::Worst, ..Fusion::default() })
+        .add("left", &left, 1.0)
+        .add("right", &right, 1.0);
+    assert_eq!(worst.combined_results(1).unwrap(),
+               vec![(1, 1.0, 3), (2, 6.0, 1), (3, 12.0, 3), (4, 8.0, 2)]);
+    let median = Combination::new(Fusion { missing : Missing::Median, ..Fusion::default() })
+        .add("left", &left, 1.0)
+        .add("right", &right, 1.0);
+    assert_eq!(median.combined_results(1).unwrap(),
+               vec![(1, 1.0, 3), (2, 4.0, 1), (3, 12.0, 3), (4, 4.0, 2)]);
+    assert_eq!(median.names(2), vec!["right"]);
+}
+
+#[test]
+fn test_fallback() {
+    let left = Fixed(vec![(1, 1.0), (2, 2.0)]);
+    let strict = Combination::new(Fusion::default())
+        .add("left", &left, 1.0)
+        .add("right", &Absent, 1.0);
+    assert_eq!(strict.combined_results(1), None);
+    let fallback = Combination::new(Fusion { fallback : true, ..Fusion::default() })
+        .add("left", &left, 1.0)
+        .add("right", &Absent, 1.0);
+    assert_eq!(fallback.combined_results(1).unwrap(), vec![(1, 1.0, 1), (2, 2.0, 1)]);
+}
+
+#[test]
+fn test_three_sources() {
+    let a = Fixed(vec![(1, 1.0), (2, 2.0), (3, 3.0), (5, 1.0)]);
+    let b = Fixed(vec![(2, 1.0), (3, 2.0), (5, 2.0)]);
+    let c = Fixed(vec![(1, 1.0), (3, 0.5), (4, 1.0), (5, 3.0)]);
+    let results = Combination::new(Fusion::default())
+        .add("a", &a, 1.0)
+        .add("b", &b, 1.0)
+        .add("c", &c, 2.0)
+        .scored_results(5).unwrap();
+    assert_eq!(results, vec![(3, 1.5), (5, 18.0)]);
+}
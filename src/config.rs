@@ -0,0 +1,530 @@
+//! Server and data configuration.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+
+use toml::{Parser,Value};
+
+use combination::Missing;
+use distance::Metric;
+use features::Normalization;
+use nounset::Representation;
+use topic::Measure;
+
+/// Prefix of environment variables overriding configuration settings.
+pub const ENV_PREFIX : &'static str = "ASHURBANIPAL_";
+
+/// Complete server configuration.
+///
+/// Settings are named by `section.name`, matching the layout of the
+/// TOML configuration file:
+///
+/// ```
+/// [server]
+/// address = "127.0.0.1"
+/// port    = 8080
+/// prefix  = "ashurbanipal.web/data/file"
+/// threads = 4
+/// scan_threads = 4
+///
+/// [data]
+/// style    = "data/gutenberg.pos"
+/// topic    = "data/gutenberg.nouns"
+/// metadata = "data/gutenberg.metadata"
+/// weights  = "data/pos.weights"
+/// vocabulary = "data/gutenberg.vocabulary"
+/// neighbors = "data/gutenberg.neighbors"
+/// neighbor_count = 100
+///
+/// [style]
+/// normalization = "zscore"
+/// metric = "cosine"
+/// index  = true
+/// budget = 2000
+///
+/// [topic]
+/// sets  = "compressed"
+/// metric = "idf_jaccard"
+/// lsh   = true
+/// bands = 20
+/// rows  = 5
+///
+/// [query]
+/// default_limit = 20
+/// max_limit     = 100
+/// strict        = false
+///
+/// [combination]
+/// missing  = "skip"
+/// fallback = false
+///
+/// [cache]
+/// capacity = 1000
+/// bytes    = 268435456
+/// ```
+#[derive(Clone,Debug)]
+pub struct Config {
+    pub server : ServerConfig,
+    pub data   : DataConfig,
+    pub style  : StyleConfig,
+    pub topic  : TopicConfig,
+    pub query  : QueryConfig,
+    pub combination : CombinationConfig,
+    pub cache  : CacheConfig,
+}
+
+/// HTTP server settings.
+#[derive(Clone,Debug)]
+pub struct ServerConfig {
+    /// Address on which to listen.
+    pub address : String,
+    /// Port on which to listen.
+    pub port    : u16,
+    /// Route prefix for all handlers, without leading or trailing slashes.
+    pub prefix  : String,
+    /// Number of request-handling threads; None uses the server default.
+    pub threads : Option<usize>,
+    /// Number of threads used to score each style or topic query.
+    pub scan_threads : usize,
+}
+
+/// Locations of the data files.
+#[derive(Clone,Debug)]
+pub struct DataConfig {
+    pub style    : Option<PathBuf>,
+    pub topic    : Option<PathBuf>,
+    pub metadata : Option<PathBuf>,
+    /// Weight of each part-of-speech feature, one per line.
+    pub weights  : Option<PathBuf>,
+    /// Noun named by each noun number of the topic data.
+    pub vocabulary : Option<PathBuf>,
+    /// Precomputed neighbor tables, used if the file exists.
+    pub neighbors : Option<PathBuf>,
+    /// Number of neighbors computed for each etext by `--precompute`.
+    pub neighbor_count : usize,
+}
+
+/// Style recommendation settings.
+#[derive(Clone,Debug)]
+pub struct StyleConfig {
+    /// Rescaling of part-of-speech features at load time.
+    pub normalization : Normalization,
+    /// Distance between part-of-speech vectors, unless a request
+    /// selects another.
+    pub metric : Metric,
+    /// Build an index for approximate nearest-neighbor searches.
+    pub index  : bool,
+    /// Maximum number of rows scored by an approximate search.
+    pub budget : usize,
+}
+
+/// Topic recommendation settings.
+#[derive(Clone,Debug)]
+pub struct TopicConfig {
+    /// Representation of the sets of nouns.
+    pub sets  : Representation,
+    /// Similarity between sets of nouns, unless a request selects
+    /// another.
+    pub metric : Measure,
+    /// Build a locality-sensitive hash index to find candidates.
+    pub lsh   : bool,
+    /// Number of bands in the MinHash signatures.
+    pub bands : usize,
+    /// Number of values in each band.
+    pub rows  : usize,
+}
+
+/// Request handling settings.
+#[derive(Clone,Debug)]
+pub struct QueryConfig {
+    /// Page size used when a request does not supply `limit`.
+    pub default_limit : usize,
+    /// Largest page size a request may ask for.
+    pub max_limit     : usize,
+    /// Reject requests with parameters the handler does not accept.
+    pub strict        : bool,
+}
+
+/// Defaults for combined recommendations.
+#[derive(Clone,Debug)]
+pub struct CombinationConfig {
+    /// Treatment of candidate etexts missing from style or topic data.
+    pub missing  : Missing,
+    /// Combine the available data when the query etext is missing
+    /// from style or topic data.
+    pub fallback : bool,
+}
+
+/// Bounds of the cache of scored results.
+#[derive(Clone,Debug)]
+pub struct CacheConfig {
+    /// Maximum number of cached results; 0 disables the cache.
+    pub capacity : usize,
+    /// Maximum total size of the cached results, in bytes.
+    pub bytes    : usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            server : ServerConfig {
+                address : "127.0.0.1".to_string(),
+                port    : 8080,
+                prefix  : "ashurbanipal.web/data/file".to_string(),
+                threads : None,
+                scan_threads : 1,
+            },
+            data : DataConfig {
+                style    : None,
+                topic    : None,
+                metadata : None,
+                weights  : None,
+                vocabulary : None,
+                neighbors : None,
+                neighbor_count : 100,
+            },
+            style : StyleConfig {
+                normalization : Normalization::None,
+                metric : Metric::Euclidean,
+                index  : false,
+                budget : 2000,
+            },
+            topic : TopicConfig {
+                sets  : Representation::Dense,
+                metric : Measure::Jaccard,
+                lsh   : false,
+                bands : 20,
+                rows  : 5,
+            },
+            query : QueryConfig {
+                default_limit : 20,
+                max_limit     : 100,
+                strict        : false,
+            },
+            combination : CombinationConfig {
+                missing  : Missing::Skip,
+                fallback : false,
+            },
+            cache : CacheConfig {
+                capacity : 1000,
+                bytes    : 256 * 1024 * 1024,
+            },
+        }
+    }
+}
+
+impl Config {
+
+    /// Change a single setting, given its `section.name` key and a
+    /// textual value.
+    ///
+    /// This is the common path for the configuration file, the
+    /// environment, and the command line, so that every setting can
+    /// be supplied from any of them.
+    pub fn set(&mut self, key : &str, value : &str) -> Result<(),String> {
+        match key {
+            "server.address"      => { self.server.address = value.to_string(); }
+            "server.port"         => { self.server.port = try!(parse(key, value)); }
+            "server.prefix"       => { self.server.prefix = value.trim_matches('/').to_string(); }
+            "server.threads"      => { self.server.threads = Some(try!(parse(key, value))); }
+            "server.scan_threads" => { self.server.scan_threads = try!(parse(key, value)); }
+            "data.style"          => { self.data.style = Some(PathBuf::from(value)); }
+            "data.topic"          => { self.data.topic = Some(PathBuf::from(value)); }
+            "data.metadata"       => { self.data.metadata = Some(PathBuf::from(value)); }
+            "data.weights"        => { self.data.weights = Some(PathBuf::from(value)); }
+            "data.vocabulary"     => { self.data.vocabulary = Some(PathBuf::from(value)); }
+            "data.neighbors"      => { self.data.neighbors = Some(PathBuf::from(value)); }
+            "data.neighbor_count" => { self.data.neighbor_count = try!(parse(key, value)); }
+            "style.normalization" => { self.style.normalization = try!(parse(key, value)); }
+            "style.metric"        => { self.style.metric = try!(parse(key, value)); }
+            "style.index"         => { self.style.index = try!(parse(key, value)); }
+            "style.budget"        => { self.style.budget = try!(parse(key, value)); }
+            "topic.sets"          => { self.topic.sets = try!(parse(key, value)); }
+            "topic.metric"        => { self.topic.metric = try!(parse(key, value)); }
+            "topic.lsh"           => { self.topic.lsh = try!(parse(key, value)); }
+            "topic.bands"         => { self.topic.bands = try!(parse(key, value)); }
+            "topic.rows"          => { self.topic.rows = try!(parse(key, value)); }
+            "query.default_limit" => { self.query.default_limit = try!(parse(key, value)); }
+            "query.max_limit"     => { self.query.max_limit = try!(parse(key, value)); }
+            "query.strict"        => { self.query.strict = try!(parse(key, value)); }
+            "combination.missing" => { self.combination.missing = try!(parse(key, value)); }
+            "combination.fallback" => { self.combination.fallback = try!(parse(key, value)); }
+            "cache.capacity"      => { self.cache.capacity = try!(parse(key, value)); }
+            "cache.bytes"         => { self.cache.bytes = try!(parse(key, value)); }
+            _                     => { return Err(unknown(key)); }
+        }
+        Ok(())
+    }
+
+    /// Apply the settings from a TOML document.
+    pub fn merge_toml(&mut self, source : &str) -> Result<(),String> {
+        let mut parser = Parser::new(source);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None        => {
+                let messages : Vec<String> = parser.errors.iter()
+                    .map( |e| {
+                        let (line,col) = parser.to_linecol(e.lo);
+                        format!("line {}, column {}: {}", line + 1, col + 1, e.desc)
+                    })
+                    .collect();
+                return Err(messages.join("; "));
+            }
+        };
+        for (section, settings) in table.iter() {
+            let settings = match *settings {
+                Value::Table(ref settings) => settings,
+                _                          => return Err(format!("not a section: {}", section)),
+            };
+            for (name, value) in settings.iter() {
+                let key = format!("{}.{}", section, name);
+                let value = match *value {
+                    Value::String(ref s) => s.clone(),
+                    Value::Integer(i)    => i.to_string(),
+                    Value::Float(f)      => f.to_string(),
+                    Value::Boolean(b)    => b.to_string(),
+                    _                    => return Err(format!("unsupported value for {}", key)),
+                };
+                try!(self.set(&key, &value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the settings from a TOML configuration file.
+    pub fn merge_file<P : AsRef<Path>>(&mut self, path : P) -> Result<(),String> {
+        let path = path.as_ref();
+        let mut source = String::new();
+        try!(File::open(path)
+             .and_then( |mut f| f.read_to_string(&mut source) )
+             .map_err( |e| format!("{}: {}", path.display(), e.description()) ));
+        self.merge_toml(&source).map_err( |e| format!("{}: {}", path.display(), e) )
+    }
+
+    /// Apply settings from environment variables.
+    ///
+    /// A variable named `ASHURBANIPAL_SECTION_NAME` sets
+    /// `section.name`; for example, `ASHURBANIPAL_QUERY_MAX_LIMIT`
+    /// sets `query.max_limit`. `ASHURBANIPAL_CONFIG`, naming the
+    /// configuration file, is ignored here.
+    ///
+    /// Variables naming no setting are ignored, since the environment
+    /// may hold other programs' variables; a warning for each is
+    /// returned.
+    pub fn merge_env<I : Iterator<Item=(String,String)>>(&mut self, vars : I) -> Result<Vec<String>,String> {
+        let mut warnings = Vec::new();
+        for (var, value) in vars {
+            if !var.starts_with(ENV_PREFIX) || var == "ASHURBANIPAL_CONFIG" { continue; }
+            let name = var[ENV_PREFIX.len()..].to_lowercase();
+            let key = name.replacen("_", ".", 1);
+            match self.set(&key, &value) {
+                Ok(())                            => { }
+                Err(ref e) if *e == unknown(&key) => { warnings.push(format!("ignoring {}: {}", var, e)); }
+                Err(e)                            => { return Err(format!("{}: {}", var, e)); }
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// The socket address the server should listen on.
+    pub fn socket_addr(&self) -> Result<SocketAddr,String> {
+        format!("{}:{}", self.server.address, self.server.port).parse()
+            .map_err( |_| format!("invalid server address: {}:{}", self.server.address, self.server.port) )
+    }
+
+    /// Check the configuration, returning a description of every
+    /// problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.socket_addr() {
+            problems.push(e);
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must be non-zero".to_string());
+        }
+        if self.server.prefix.is_empty() {
+            problems.push("server.prefix must not be empty".to_string());
+        }
+        if self.server.threads == Some(0) {
+            problems.push("server.threads must be at least 1".to_string());
+        }
+        if self.server.scan_threads == 0 {
+            problems.push("server.scan_threads must be at least 1".to_string());
+        }
+        if self.data.neighbor_count == 0 {
+            problems.push("data.neighbor_count must be at least 1".to_string());
+        }
+        if self.style.index && !self.style.metric.is_metric() {
+            problems.push(format!("style.index cannot be used with the {} distance", self.style.metric.name()));
+        }
+        if self.style.metric == Metric::Mahalanobis && self.data.weights.is_some() {
+            // Scaling a dimension scales its variance to match.
+            problems.push("data.weights cannot be used with the mahalanobis distance, which ignores them".to_string());
+        }
+        if self.style.budget == 0 {
+            problems.push("style.budget must be at least 1".to_string());
+        }
+        if self.topic.bands == 0 {
+            problems.push("topic.bands must be at least 1".to_string());
+        }
+        if self.topic.rows == 0 {
+            problems.push("topic.rows must be at least 1".to_string());
+        }
+        if self.query.default_limit == 0 {
+            problems.push("query.default_limit must be at least 1".to_string());
+        }
+        if self.query.max_limit < self.query.default_limit {
+            problems.push(format!("query.max_limit ({}) is less than query.default_limit ({})",
+                                  self.query.max_limit, self.query.default_limit));
+        }
+        for &(key, ref path) in [("data.style", &self.data.style),
+                                 ("data.topic", &self.data.topic),
+                                 ("data.metadata", &self.data.metadata)].iter() {
+            match **path {
+                None                          => problems.push(format!("{} is required", key)),
+                Some(ref p) if !p.is_file()   => problems.push(format!("{}: no such file: {}", key, p.display())),
+                Some(_)                       => { }
+            }
+        }
+        for &(key, ref path) in [("data.weights", &self.data.weights),
+                                 ("data.vocabulary", &self.data.vocabulary)].iter() {
+            if let Some(ref p) = **path {
+                if !p.is_file() {
+                    problems.push(format!("{}: no such file: {}", key, p.display()));
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// The error for a key naming no setting.
+fn unknown(key : &str) -> String {
+    format!("unknown setting: {}", key)
+}
+
+fn parse<T : FromStr>(key : &str, value : &str) -> Result<T,String> {
+    value.trim().parse().map_err( |_| format!("invalid value for {}: {}", key, value) )
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        fn path(p : &Option<PathBuf>) -> String {
+            p.as_ref().map( |p| p.display().to_string() ).unwrap_or("(unset)".to_string())
+        }
+        let settings = vec![
+            ("server.address",       self.server.address.clone()),
+            ("server.port",          self.server.port.to_string()),
+            ("server.prefix",        self.server.prefix.clone()),
+            ("server.threads",       self.server.threads.map( |t| t.to_string() ).unwrap_or("(default)".to_string())),
+            ("server.scan_threads",  self.server.scan_threads.to_string()),
+            ("data.style",           path(&self.data.style)),
+            ("data.topic",           path(&self.data.topic)),
+            ("data.metadata",        path(&self.data.metadata)),
+            ("data.weights",         path(&self.data.weights)),
+            ("data.vocabulary",      path(&self.data.vocabulary)),
+            ("data.neighbors",       path(&self.data.neighbors)),
+            ("data.neighbor_count",  self.data.neighbor_count.to_string()),
+            ("style.normalization",  self.style.normalization.name().to_string()),
+            ("style.metric",         self.style.metric.name().to_string()),
+            ("style.index",          self.style.index.to_string()),
+            ("style.budget",         self.style.budget.to_string()),
+            ("topic.sets",           format!("{:?}", self.topic.sets).to_lowercase()),
+            ("topic.metric",         self.topic.metric.name().to_string()),
+            ("topic.lsh",            self.topic.lsh.to_string()),
+            ("topic.bands",          self.topic.bands.to_string()),
+            ("topic.rows",           self.topic.rows.to_string()),
+            ("query.default_limit",  self.query.default_limit.to_string()),
+            ("query.max_limit",      self.query.max_limit.to_string()),
+            ("query.strict",         self.query.strict.to_string()),
+            ("combination.missing",  format!("{:?}", self.combination.missing).to_lowercase()),
+            ("combination.fallback", self.combination.fallback.to_string()),
+            ("cache.capacity",       self.cache.capacity.to_string()),
+            ("cache.bytes",          self.cache.bytes.to_string()),
+        ];
+        for &(key, ref value) in settings.iter() {
+            try!(writeln!(f, "{:20} = {}", key, value));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_set() {
+    let mut config = Config::default();
+    assert!(config.set("server.port", "9090").is_ok());
+    assert_eq!(config.server.port, 9090);
+    assert!(config.set("server.prefix", "/api/v1/").is_ok());
+    assert_eq!(config.server.prefix, "api/v1");
+    assert!(config.set("server.port", "ninety").is_err());
+    assert!(config.set("server.bogus", "1").is_err());
+}
+
+#[test]
+fn test_toml() {
+    let mut config = Config::default();
+    let source = "[server]\nport = 8000\nthreads = 8\n[query]\nmax_limit = 50\nstrict = true\n";
+    assert!(config.merge_toml(source).is_ok());
+    assert_eq!(config.server.port, 8000);
+    assert_eq!(config.server.threads, Some(8));
+    assert_eq!(config.query.max_limit, 50);
+    assert_eq!(config.query.default_limit, 20);
+    assert!(config.query.strict);
+    assert!(config.merge_toml("[server]\nport = \n").is_err());
+    assert!(config.merge_toml("[query]\nmaximum = 3\n").is_err());
+}
+
+#[test]
+fn test_env() {
+    let mut config = Config::default();
+    let vars = vec![("ASHURBANIPAL_QUERY_DEFAULT_LIMIT".to_string(), "10".to_string()),
+                    ("ASHURBANIPAL_CONFIG".to_string(), "ignored.toml".to_string()),
+                    ("HOME".to_string(), "/root".to_string())];
+    assert_eq!(config.merge_env(vars.into_iter()), Ok(Vec::new()));
+    assert_eq!(config.query.default_limit, 10);
+    let vars = vec![("ASHURBANIPAL_DEPLOY_COLOR".to_string(), "blue".to_string()),
+                    ("ASHURBANIPAL_QUERY_MAX_LIMIT".to_string(), "50".to_string())];
+    let warnings = config.merge_env(vars.into_iter()).unwrap();
+    assert_eq!(warnings, vec!["ignoring ASHURBANIPAL_DEPLOY_COLOR: unknown setting: deploy.color".to_string()]);
+    assert_eq!(config.query.max_limit, 50);
+    let vars = vec![("ASHURBANIPAL_QUERY_MAX_LIMIT".to_string(), "fifty".to_string())];
+    assert!(config.merge_env(vars.into_iter()).is_err());
+}
+
+#[test]
+fn test_validate() {
+    let mut config = Config::default();
+    config.query.default_limit = 200;
+    let problems = config.validate();
+    assert!(problems.iter().any( |p| p.starts_with("query.max_limit") ));
+    assert!(problems.iter().any( |p| p == "data.style is required" ));
+    assert!(!problems.iter().any( |p| p.starts_with("data.weights") ));
+    config.style.metric = Metric::Mahalanobis;
+    config.data.weights = Some(PathBuf::from("pos.weights"));
+    assert!(config.validate().iter().any( |p| p.starts_with("data.weights cannot be used") ));
+}
@@ -0,0 +1,350 @@
+//! Distance functions over part-of-speech vectors.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::ops::Add;
+use std::str::FromStr;
+
+use matrix::Matrix2;
+use recommendation::Score;
+
+/// A measure of the difference between two vectors of equal length.
+pub trait Distance : Sync {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score;
+
+    /// The part of the distance due to each dimension. By default,
+    /// the difference in each dimension: these sum to the Manhattan
+    /// distance, and the largest is the Chebyshev distance.
+    fn contributions(&self, v1 : &[Score], v2 : &[Score]) -> Vec<Score> {
+        assert_eq!(v1.len(), v2.len());
+        v1.iter().zip(v2.iter()).map( |(x,y)| (x - y).abs() ).collect()
+    }
+}
+
+/// The distances selectable by configuration or query parameter.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Metric {
+    Euclidean,
+    Manhattan,
+    Cosine,
+    Chebyshev,
+    BrayCurtis,
+    Mahalanobis,
+}
+
+impl Metric {
+    /// The name of the metric, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Metric::Euclidean   => "euclidean",
+            Metric::Manhattan   => "manhattan",
+            Metric::Cosine      => "cosine",
+            Metric::Chebyshev   => "chebyshev",
+            Metric::BrayCurtis  => "braycurtis",
+            Metric::Mahalanobis => "mahalanobis",
+        }
+    }
+
+    /// True if the distance obeys the triangle inequality, as an
+    /// index such as `VpTree` requires.
+    pub fn is_metric(&self) -> bool {
+        match *self {
+            Metric::Cosine | Metric::BrayCurtis => false,
+            _                                   => true,
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = ();
+
+    fn from_str(s : &str) -> Result<Metric,()> {
+        match s {
+            "euclidean"   => Ok(Metric::Euclidean),
+            "manhattan"   => Ok(Metric::Manhattan),
+            "cosine"      => Ok(Metric::Cosine),
+            "chebyshev"   => Ok(Metric::Chebyshev),
+            "braycurtis"  => Ok(Metric::BrayCurtis),
+            "mahalanobis" => Ok(Metric::Mahalanobis),
+            _             => Err(()),
+        }
+    }
+}
+
+/// Straight-line distance.
+pub struct Euclidean;
+
+impl Distance for Euclidean {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score {
+        assert_eq!(v1.len(), v2.len());
+        let sq = v1.iter()
+            // Match each element with that from the other vector.
+            .zip( v2.iter() )
+            // Compute (elt1 - elt2)^2.
+            .map( |(x,y)| Score::powi(x-y,2) )
+            // Accumulate the value.
+            .fold(0 as Score, Add::add);
+        Score::sqrt(sq)
+    }
+
+    /// The squared difference in each dimension, which sum to the
+    /// square of the distance.
+    fn contributions(&self, v1 : &[Score], v2 : &[Score]) -> Vec<Score> {
+        assert_eq!(v1.len(), v2.len());
+        v1.iter().zip(v2.iter()).map( |(x,y)| Score::powi(x-y,2) ).collect()
+    }
+}
+
+/// Sum of the differences in each dimension.
+pub struct Manhattan;
+
+impl Distance for Manhattan {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score {
+        assert_eq!(v1.len(), v2.len());
+        v1.iter().zip(v2.iter()).fold(0 as Score, |acc,(x,y)| acc + (x - y).abs())
+    }
+}
+
+/// One minus the cosine of the angle between the vectors, ignoring
+/// their lengths. A zero vector is at distance 1 from everything.
+pub struct Cosine;
+
+impl Distance for Cosine {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score {
+        assert_eq!(v1.len(), v2.len());
+        let (dot, n1, n2) = v1.iter().zip(v2.iter())
+            .fold((0 as Score, 0 as Score, 0 as Score), |(d,n1,n2),(x,y)| (d + x * y, n1 + x * x, n2 + y * y));
+        if n1 == 0.0 || n2 == 0.0 { 1.0 } else { 1.0 - dot / (n1.sqrt() * n2.sqrt()) }
+    }
+
+    /// Half the squared difference of the unit vectors in each
+    /// dimension, which sum to the distance. With a zero vector, the
+    /// dimensions contribute equally.
+    fn contributions(&self, v1 : &[Score], v2 : &[Score]) -> Vec<Score> {
+        assert_eq!(v1.len(), v2.len());
+        let (n1, n2) = v1.iter().zip(v2.iter())
+            .fold((0 as Score, 0 as Score), |(n1,n2),(x,y)| (n1 + x * x, n2 + y * y));
+        if n1 == 0.0 || n2 == 0.0 { return vec![1.0 / v1.len() as Score; v1.len()]; }
+        let (n1, n2) = (n1.sqrt(), n2.sqrt());
+        v1.iter().zip(v2.iter()).map( |(x,y)| Score::powi(x / n1 - y / n2, 2) / 2.0 ).collect()
+    }
+}
+
+/// Largest difference in any dimension.
+pub struct Chebyshev;
+
+impl Distance for Chebyshev {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score {
+        assert_eq!(v1.len(), v2.len());
+        v1.iter().zip(v2.iter()).fold(0 as Score, |acc,(x,y)| Score::max(acc, (x - y).abs()))
+    }
+}
+
+/// Sum of the differences divided by the sum of the values, for
+/// non-negative vectors such as proportions. Two zero vectors are at
+/// distance 0.
+pub struct BrayCurtis;
+
+impl Distance for BrayCurtis {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score {
+        assert_eq!(v1.len(), v2.len());
+        let (difference, sum) = v1.iter().zip(v2.iter())
+            .fold((0 as Score, 0 as Score), |(d,s),(x,y)| (d + (x - y).abs(), s + (x + y).abs()));
+        if sum == 0.0 { 0.0 } else { difference / sum }
+    }
+
+    /// The difference in each dimension divided by the sum of the
+    /// values, which sum to the distance.
+    fn contributions(&self, v1 : &[Score], v2 : &[Score]) -> Vec<Score> {
+        assert_eq!(v1.len(), v2.len());
+        let sum = v1.iter().zip(v2.iter()).fold(0 as Score, |s,(x,y)| s + (x + y).abs());
+        v1.iter().zip(v2.iter()).map( |(x,y)| if sum == 0.0 { 0.0 } else { (x - y).abs() / sum } ).collect()
+    }
+}
+
+/// Distance scaled by the covariance of the data, so that dimensions
+/// which vary widely, or together, count for less.
+pub struct Mahalanobis {
+    /// Inverse of the covariance matrix, by rows.
+    inverse : Vec<Vec<Score>>,
+}
+
+impl Mahalanobis {
+
+    /// Compute the covariance of the rows of `data`, and its inverse.
+    ///
+    /// Part-of-speech proportions sum to one, which makes their
+    /// covariance singular. A small amount is added to the diagonal
+    /// before inverting so that the inverse exists, which changes the
+    /// distances slightly.
+    pub fn new(data : &Matrix2<Score>) -> Mahalanobis {
+        let mut covariance = covariance(data);
+        let d = covariance.len();
+        let trace = (0..d).fold(0.0, |acc,i| acc + covariance[i][i]);
+        let ridge = if trace > 0.0 { 1e-6 * trace / d as Score } else { 1.0 };
+        for i in 0..d {
+            covariance[i][i] += ridge;
+        }
+        // A positive-definite matrix is always invertible.
+        Mahalanobis { inverse : panic_unless!("covariance matrix", option: invert(covariance)) }
+    }
+}
+
+impl Distance for Mahalanobis {
+    fn distance(&self, v1 : &[Score], v2 : &[Score]) -> Score {
+        assert_eq!(v1.len(), v2.len());
+        assert_eq!(v1.len(), self.inverse.len());
+        let diff : Vec<Score> = v1.iter().zip(v2.iter()).map( |(x,y)| x - y ).collect();
+        let sq = self.inverse.iter().zip(diff.iter())
+            .fold(0 as Score, |acc,(row,di)| {
+                acc + di * row.iter().zip(diff.iter()).fold(0 as Score, |acc,(s,dj)| acc + s * dj)
+            });
+        // Rounding may leave a tiny negative value.
+        Score::max(sq, 0.0).sqrt()
+    }
+
+    /// The difference in each dimension times that dimension's row
+    /// of the scaled difference. These sum to the square of the
+    /// distance; correlated dimensions may contribute negatively.
+    fn contributions(&self, v1 : &[Score], v2 : &[Score]) -> Vec<Score> {
+        assert_eq!(v1.len(), v2.len());
+        assert_eq!(v1.len(), self.inverse.len());
+        let diff : Vec<Score> = v1.iter().zip(v2.iter()).map( |(x,y)| x - y ).collect();
+        self.inverse.iter().zip(diff.iter())
+            .map( |(row,di)| di * row.iter().zip(diff.iter()).fold(0 as Score, |acc,(s,dj)| acc + s * dj) )
+            .collect()
+    }
+}
+
+/// The covariance matrix of the columns of `data`.
+fn covariance(data : &Matrix2<Score>) -> Vec<Vec<Score>> {
+    let cols = data.rows().next().map( |r| r.len() ).unwrap_or(0);
+    let n = data.rows().count();
+    let mut mean = vec![0 as Score; cols];
+    for row in data.rows() {
+        for (m, x) in mean.iter_mut().zip(row.iter()) { *m += *x; }
+    }
+    for m in mean.iter_mut() { *m /= n as Score; }
+    let mut covariance = vec![vec![0 as Score; cols]; cols];
+    for row in data.rows() {
+        for i in 0..cols {
+            let di = row[i] - mean[i];
+            for j in i..cols {
+                covariance[i][j] += di * (row[j] - mean[j]);
+            }
+        }
+    }
+    let divisor = if n > 1 { (n - 1) as Score } else { 1.0 };
+    for i in 0..cols {
+        for j in i..cols {
+            covariance[i][j] /= divisor;
+            covariance[j][i] = covariance[i][j];
+        }
+    }
+    covariance
+}
+
+/// Invert a square matrix by Gauss-Jordan elimination with partial
+/// pivoting, returning None if it is singular.
+pub fn invert(mut m : Vec<Vec<Score>>) -> Option<Vec<Vec<Score>>> {
+    let n = m.len();
+    let mut inverse : Vec<Vec<Score>> = (0..n)
+        .map( |i| (0..n).map( |j| if i == j { 1.0 } else { 0.0 } ).collect() )
+        .collect();
+    for col in 0..n {
+        // Use the row with the largest value in this column.
+        let pivot = (col..n).fold(col, |best,r| if m[r][col].abs() > m[best][col].abs() { r } else { best });
+        if m[pivot][col].abs() < 1e-300 { return None; }
+        m.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = m[col][col];
+        for j in 0..n {
+            m[col][j] /= scale;
+            inverse[col][j] /= scale;
+        }
+        for r in 0..n {
+            if r == col { continue; }
+            let factor = m[r][col];
+            if factor == 0.0 { continue; }
+            for j in 0..n {
+                m[r][j] -= factor * m[col][j];
+                inverse[r][j] -= factor * inverse[col][j];
+            }
+        }
+    }
+    Some(inverse)
+}
+
+#[cfg(test)]
+fn close(x : Score, y : Score) -> bool { (x - y).abs() < 1e-9 }
+
+#[test]
+fn test_distances() {
+    let (v1, v2) = ([1.0, 2.0, 3.0], [4.0, 0.0, 3.0]);
+    assert!(close(Euclidean.distance(&v1, &v2), 13f64.sqrt()));
+    assert!(close(Manhattan.distance(&v1, &v2), 5.0));
+    assert!(close(Chebyshev.distance(&v1, &v2), 3.0));
+    assert!(close(BrayCurtis.distance(&v1, &v2), 5.0 / 13.0));
+    assert!(close(Cosine.distance(&v1, &v2), 1.0 - 13.0 / (14f64.sqrt() * 5.0)));
+    assert!(close(Cosine.distance(&v1, &[2.0, 4.0, 6.0]), 0.0));
+    assert_eq!(Cosine.distance(&v1, &[0.0, 0.0, 0.0]), 1.0);
+    assert_eq!(BrayCurtis.distance(&[0.0, 0.0], &[0.0, 0.0]), 0.0);
+}
+
+#[test]
+fn test_invert() {
+    let m = vec![vec![4.0, 7.0], vec![2.0, 6.0]];
+    let inverse = invert(m).unwrap();
+    let expected = [[0.6, -0.7], [-0.2, 0.4]];
+    for i in 0..2 {
+        for j in 0..2 {
+            assert!(close(inverse[i][j], expected[i][j]));
+        }
+    }
+    assert!(invert(vec![vec![1.0, 2.0], vec![2.0, 4.0]]).is_none());
+}
+
+#[test]
+fn test_mahalanobis() {
+    // With the identity as covariance, Mahalanobis is Euclidean.
+    let identity = Mahalanobis { inverse : vec![vec![1.0, 0.0], vec![0.0, 1.0]] };
+    assert!(close(identity.distance(&[1.0, 2.0], &[4.0, 6.0]), 5.0));
+    // A dimension varying ten times as widely counts a tenth as much.
+    let data = Matrix2::new(&[vec![-1.0, -10.0], vec![-1.0, 10.0], vec![1.0, -10.0], vec![1.0, 10.0]]);
+    let m = Mahalanobis::new(&data);
+    let (dx, dy) = (m.distance(&[0.0, 0.0], &[1.0, 0.0]), m.distance(&[0.0, 0.0], &[0.0, 10.0]));
+    assert!((dx - dy).abs() < 1e-4, "{} != {}", dx, dy);
+    for &metric in ["euclidean", "manhattan", "cosine", "chebyshev", "braycurtis", "mahalanobis"].iter() {
+        assert_eq!(metric.parse::<Metric>().unwrap().name(), metric);
+    }
+}
+
+#[test]
+fn test_contributions() {
+    let (v1, v2) = ([1.0, 2.0, 3.0], [4.0, 0.0, 3.0]);
+    let sum = |d : &Distance| d.contributions(&v1, &v2).iter().fold(0.0, |acc,c| acc + c);
+    assert_eq!(Manhattan.contributions(&v1, &v2), vec![3.0, 2.0, 0.0]);
+    assert!(close(sum(&Euclidean), 13.0));
+    assert!(close(sum(&Cosine), Cosine.distance(&v1, &v2)));
+    assert!(close(sum(&BrayCurtis), 5.0 / 13.0));
+    let m = Mahalanobis { inverse : vec![vec![2.0, 0.5, 0.0], vec![0.5, 1.0, 0.0], vec![0.0, 0.0, 1.0]] };
+    assert!(close(sum(&m), Score::powi(m.distance(&v1, &v2), 2)));
+}
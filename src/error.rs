@@ -0,0 +1,108 @@
+//! Errors encountered while loading data files.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+
+/// A problem with a data file, located as precisely as possible.
+#[derive(Debug)]
+pub struct LoadError {
+    /// The file being read.
+    pub path   : PathBuf,
+    /// Line number, starting at 1, if the problem is on a line.
+    pub line   : Option<usize>,
+    /// Tab-separated column, starting at 1, if the problem is in a column.
+    pub column : Option<usize>,
+    /// Description of the problem.
+    pub reason : String,
+}
+
+impl LoadError {
+
+    /// A problem with the file as a whole.
+    pub fn new<P : AsRef<Path>, S : ToString>(path : P, reason : S) -> LoadError {
+        LoadError {
+            path   : path.as_ref().to_path_buf(),
+            line   : None,
+            column : None,
+            reason : reason.to_string(),
+        }
+    }
+
+    /// A problem on a given line, and optionally in a given column,
+    /// of the file.
+    pub fn at<P : AsRef<Path>, S : ToString>(path : P, line : usize, column : Option<usize>, reason : S) -> LoadError {
+        LoadError {
+            path   : path.as_ref().to_path_buf(),
+            line   : Some(line),
+            column : column,
+            reason : reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.path.display()));
+        if let Some(line) = self.line { try!(write!(f, ":{}", line)); }
+        if let Some(column) = self.column { try!(write!(f, ":{}", column)); }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str { &self.reason }
+}
+
+/// Parse a tab-separated field of a data file, describing the
+/// expected value if it cannot be parsed.
+///
+/// `line` and `column` are counted from 1.
+pub fn parse_field<T : FromStr>(path : &Path, line : usize, column : usize, field : Option<&str>, expected : &str)
+                                -> Result<T,LoadError> {
+    match field {
+        Some(s) => s.parse().map_err( |_| LoadError::at(path, line, Some(column),
+                                                          format!("expected {}, found {:?}", expected, s)) ),
+        None    => Err(LoadError::at(path, line, Some(column), format!("missing {}", expected))),
+    }
+}
+
+#[test]
+fn test_display() {
+    let e = LoadError::at("data/gutenberg.pos", 12, Some(3), "expected proportion, found \"x\"");
+    assert_eq!(e.to_string(), "data/gutenberg.pos:12:3: expected proportion, found \"x\"");
+    let e = LoadError::new("data/gutenberg.pos", "no style data");
+    assert_eq!(e.to_string(), "data/gutenberg.pos: no style data");
+}
+
+#[test]
+fn test_parse_field() {
+    let path = Path::new("data");
+    assert_eq!(parse_field::<usize>(path, 1, 1, Some("773"), "etext number").ok(), Some(773));
+    let e = parse_field::<usize>(path, 2, 1, Some("x773"), "etext number").unwrap_err();
+    assert_eq!((e.line, e.column), (Some(2), Some(1)));
+    assert_eq!(e.reason, "expected etext number, found \"x773\"");
+    let e = parse_field::<usize>(path, 3, 4, None, "etext number").unwrap_err();
+    assert_eq!(e.reason, "missing etext number");
+}
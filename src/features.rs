@@ -0,0 +1,231 @@
+//! Normalization and weighting of part-of-speech features.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::f64;
+use std::fs::File;
+use std::io::{BufRead,BufReader};
+use std::path::Path;
+use std::str::FromStr;
+
+use rustc_serialize::{Encodable,Encoder};
+
+use error::{LoadError,parse_field};
+use matrix::Matrix2;
+use recommendation::Score;
+
+/// Added to proportions before taking logarithms, so that a tag
+/// absent from a text does not produce an infinite value.
+const LOG_OFFSET : Score = 1e-6;
+
+/// Rescaling applied to each feature before comparing texts.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Normalization {
+    /// Raw proportions.
+    None,
+    /// Distance from the column mean, in standard deviations.
+    ZScore,
+    /// Position between the column minimum (0) and maximum (1).
+    MinMax,
+    /// Logarithm of the ratio of the proportion to the column mean.
+    LogRatio,
+}
+
+impl Normalization {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Normalization::None     => "none",
+            Normalization::ZScore   => "zscore",
+            Normalization::MinMax   => "minmax",
+            Normalization::LogRatio => "logratio",
+        }
+    }
+}
+
+/// Encoded by name, as in the configuration.
+impl Encodable for Normalization {
+    fn encode<S : Encoder>(&self, s : &mut S) -> Result<(),S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = ();
+
+    fn from_str(s : &str) -> Result<Normalization,()> {
+        match s {
+            "none"     => Ok(Normalization::None),
+            "zscore"   => Ok(Normalization::ZScore),
+            "minmax"   => Ok(Normalization::MinMax),
+            "logratio" => Ok(Normalization::LogRatio),
+            _          => Err(()),
+        }
+    }
+}
+
+/// Statistics of one feature over the corpus.
+#[derive(Clone,Debug,RustcEncodable)]
+pub struct ColumnStats {
+    pub mean      : Score,
+    pub deviation : Score,
+    pub min       : Score,
+    pub max       : Score,
+}
+
+/// The normalization and weights applied to every part-of-speech
+/// vector, with the column statistics they are based on.
+#[derive(Clone,Debug,RustcEncodable)]
+pub struct Transform {
+    pub normalization : Normalization,
+    /// Statistics of the raw features.
+    pub columns       : Vec<ColumnStats>,
+    /// Multiplier of each normalized feature.
+    pub weights       : Vec<Score>,
+}
+
+impl Transform {
+
+    /// Compute the column statistics of `data` for `normalization`.
+    /// Without `weights`, every feature has weight 1.
+    ///
+    /// # Panics
+    ///
+    /// There must be as many weights as columns.
+    pub fn new(data : &Matrix2<Score>, normalization : Normalization, weights : Option<Vec<Score>>) -> Transform {
+        let columns = column_stats(data);
+        let weights = weights.unwrap_or(vec![1.0; columns.len()]);
+        assert_eq!(weights.len(), columns.len());
+        Transform {
+            normalization : normalization,
+            columns       : columns,
+            weights       : weights,
+        }
+    }
+
+    /// True if some feature has a weight other than 1.
+    pub fn is_weighted(&self) -> bool {
+        self.weights.iter().any( |&w| w != 1.0 )
+    }
+
+    /// Normalize and weight a raw vector.
+    pub fn apply(&self, raw : &[Score]) -> Vec<Score> {
+        assert_eq!(raw.len(), self.columns.len());
+        raw.iter().zip(self.columns.iter()).zip(self.weights.iter())
+            .map( |((&x, c), &w)| {
+                let x = match self.normalization {
+                    Normalization::None     => x,
+                    Normalization::ZScore   => if c.deviation > 0.0 { (x - c.mean) / c.deviation } else { x - c.mean },
+                    Normalization::MinMax   => if c.max > c.min { (x - c.min) / (c.max - c.min) } else { 0.0 },
+                    Normalization::LogRatio => ((x + LOG_OFFSET) / (c.mean + LOG_OFFSET)).ln(),
+                };
+                x * w
+            })
+            .collect()
+    }
+
+    /// Normalize and weight every row of `data`.
+    pub fn apply_all(&self, data : &Matrix2<Score>) -> Matrix2<Score> {
+        let rows : Vec<Vec<Score>> = data.rows().map( |r| self.apply(r) ).collect();
+        Matrix2::new(&rows)
+    }
+}
+
+/// The mean, standard deviation, minimum and maximum of each column.
+fn column_stats(data : &Matrix2<Score>) -> Vec<ColumnStats> {
+    let cols = data.rows().next().map( |r| r.len() ).unwrap_or(0);
+    let mut stats : Vec<ColumnStats> = (0..cols)
+        .map( |_| ColumnStats { mean : 0.0, deviation : 0.0, min : f64::INFINITY, max : f64::NEG_INFINITY } )
+        .collect();
+    let mut n = 0;
+    for row in data.rows() {
+        n += 1;
+        for (s, &x) in stats.iter_mut().zip(row.iter()) {
+            s.mean += x;
+            s.min = Score::min(s.min, x);
+            s.max = Score::max(s.max, x);
+        }
+    }
+    for s in stats.iter_mut() { s.mean /= n as Score; }
+    for row in data.rows() {
+        for (s, &x) in stats.iter_mut().zip(row.iter()) {
+            s.deviation += (x - s.mean) * (x - s.mean);
+        }
+    }
+    for s in stats.iter_mut() { s.deviation = (s.deviation / n as Score).sqrt(); }
+    stats
+}
+
+/// Read a file of feature weights, one per line in column order.
+/// Blank lines and lines beginning with `#` are ignored.
+///
+/// # Errors
+///
+/// Returns a `LoadError` if the file cannot be read, a weight is not
+/// a non-negative number, or there are not `dimensions` weights.
+pub fn read_weights<P : AsRef<Path>>(path : P, dimensions : usize) -> Result<Vec<Score>,LoadError> {
+    let path = path.as_ref();
+    let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+    let mut weights = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let lineno = i + 1;
+        let line = try!(line.map_err( |e| LoadError::at(path, lineno, None, e) ));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        let weight : Score = try!(parse_field(path, lineno, 1, Some(line), "weight"));
+        if !(weight >= 0.0) {
+            return Err(LoadError::at(path, lineno, Some(1), format!("weight must not be negative: {}", weight)));
+        }
+        weights.push(weight);
+    }
+    if weights.len() != dimensions {
+        return Err(LoadError::new(path, format!("expected {} weights, found {}", dimensions, weights.len())));
+    }
+    Ok(weights)
+}
+
+#[cfg(test)]
+fn sample() -> Matrix2<Score> {
+    Matrix2::new(&[vec![0.1, 0.5], vec![0.3, 0.5], vec![0.2, 0.5]])
+}
+
+#[test]
+fn test_stats() {
+    let t = Transform::new(&sample(), Normalization::None, None);
+    assert!((t.columns[0].mean - 0.2).abs() < 1e-12);
+    assert!((t.columns[0].deviation - (0.02f64 / 3.0).sqrt()).abs() < 1e-12);
+    assert_eq!((t.columns[0].min, t.columns[0].max), (0.1, 0.3));
+    assert_eq!(t.columns[1].deviation, 0.0);
+    assert_eq!(t.apply(&[0.1, 0.5]), vec![0.1, 0.5]);
+}
+
+#[test]
+fn test_normalizations() {
+    let data = sample();
+    let minmax = Transform::new(&data, Normalization::MinMax, None);
+    assert_eq!(minmax.apply(&[0.3, 0.5]), vec![1.0, 0.0]);
+    let zscore = Transform::new(&data, Normalization::ZScore, Some(vec![2.0, 1.0]));
+    let z = zscore.apply(&[0.2 + zscore.columns[0].deviation, 0.5]);
+    assert!((z[0] - 2.0).abs() < 1e-9 && z[1] == 0.0);
+    let logratio = Transform::new(&data, Normalization::LogRatio, None);
+    let l = logratio.apply(&[0.2, 0.0]);
+    assert!(l[0].abs() < 1e-9 && l[1] < -10.0);
+    assert_eq!(logratio.apply_all(&data)[2], logratio.apply(&[0.2, 0.5])[..]);
+}
@@ -23,39 +23,210 @@
 extern crate rustful;
 extern crate rustc_serialize;
 extern crate iterator_utilities;
+extern crate getopts;
+extern crate toml;
+extern crate crossbeam;
 
 #[macro_use]
 mod macros;
 
+mod cache;
 mod combination;
+mod config;
+mod distance;
+mod error;
+mod features;
 mod index;
 mod matrix;
 mod mbitset;
 mod metadata;
+mod minhash;
+mod neighbors;
+mod nounset;
 mod nysiis;
+mod parallel;
+mod params;
 mod recommendation;
+mod roaring;
+mod seeds;
 mod style;
 mod topic;
+mod vptree;
 mod web;
 
 use std::env;
 use std::error::Error;
+use std::process;
 use std::str::FromStr;
 
+use getopts::Options;
+
 use rustful::{Server,TreeRouter};
 
+use config::Config;
 use web::{RecQuery,RecState};
 
+/// Number of texts sampled by `--recall`.
+const RECALL_QUERIES : usize = 100;
+
+/// Command-line options, with the configuration setting each sets.
+const SETTINGS : &'static [(&'static str, &'static str, &'static str, &'static str)] = &[
+    ("address",        "server.address",       "address on which to listen",            "ADDR"),
+    ("port",           "server.port",          "port on which to listen",               "PORT"),
+    ("prefix",         "server.prefix",        "route prefix for all handlers",         "PATH"),
+    ("threads",        "server.threads",       "number of request threads",             "N"),
+    ("scan-threads",   "server.scan_threads",  "number of threads scoring each query",  "N"),
+    ("style",          "data.style",           "part-of-speech data file",              "FILE"),
+    ("topic",          "data.topic",           "topic (noun) data file",                "FILE"),
+    ("metadata",       "data.metadata",        "text metadata file",                    "FILE"),
+    ("weights",        "data.weights",         "part-of-speech feature weights file",   "FILE"),
+    ("vocabulary",     "data.vocabulary",      "noun vocabulary file",                  "FILE"),
+    ("neighbors",      "data.neighbors",       "precomputed neighbor table file",       "FILE"),
+    ("neighbor-count", "data.neighbor_count",  "neighbors to precompute for each text", "N"),
+    ("style-normalization", "style.normalization", "none, zscore, minmax or logratio",  "NORM"),
+    ("style-metric",   "style.metric",         "distance between part-of-speech data",  "METRIC"),
+    ("style-index",    "style.index",          "index style data for approximate search", "BOOL"),
+    ("style-budget",   "style.budget",         "rows scored per approximate search",    "N"),
+    ("topic-sets",     "topic.sets",           "noun sets: dense or compressed",        "REPR"),
+    ("topic-metric",   "topic.metric",         "similarity between sets of nouns",      "METRIC"),
+    ("topic-lsh",      "topic.lsh",            "find topic candidates by MinHash",      "BOOL"),
+    ("topic-bands",    "topic.bands",          "bands in each MinHash signature",       "N"),
+    ("topic-rows",     "topic.rows",           "values in each MinHash band",           "N"),
+    ("default-limit",  "query.default_limit",  "default number of results per page",    "N"),
+    ("max-limit",      "query.max_limit",      "maximum number of results per page",    "N"),
+    ("strict",         "query.strict",         "reject unknown query parameters",       "BOOL"),
+    ("missing",        "combination.missing",  "missing scores: skip, median or worst", "MODE"),
+    ("fallback",       "combination.fallback", "combine partial data for a query text", "BOOL"),
+    ("cache-capacity", "cache.capacity",       "number of cached results (0 disables)", "N"),
+    ("cache-bytes",    "cache.bytes",          "maximum size of cached results",        "BYTES"),
+];
+
 fn main() {
     let args : Vec<String> = env::args().collect();
-    if args.len() < 4 { panic!("Usage: ashurbanipal_web pos-data topic-data metadata"); }
+
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "read configuration from FILE (or $ASHURBANIPAL_CONFIG)", "FILE");
+    for &(name, _, description, hint) in SETTINGS.iter() {
+        opts.optopt("", name, description, hint);
+    }
+    opts.optflag("", "check-config", "validate the configuration and load the data, then exit");
+    opts.optflag("", "precompute", "write neighbor tables to the data.neighbors file, then exit");
+    opts.optflag("", "recall", "compare approximate and exact searches, then exit");
+    opts.optflag("h", "help", "print this message");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m)  => m,
+        Err(e) => { fail(&[e.to_string()]); }
+    };
+    if matches.opt_present("help") {
+        let brief = format!("Usage: {} [options] [pos-data topic-data metadata]", args[0]);
+        print!("{}", opts.usage(&brief));
+        return;
+    }
+
+    // Defaults, then the configuration file, then the environment,
+    // then the command line.
+    let mut config = Config::default();
+    let mut problems = Vec::new();
+    if let Some(path) = matches.opt_str("config").or(env::var("ASHURBANIPAL_CONFIG").ok()) {
+        if let Err(e) = config.merge_file(&path) { problems.push(e); }
+    }
+    match config.merge_env(env::vars()) {
+        Ok(warnings) => { for warning in warnings.iter() { eprintln!("warning: {}", warning); } }
+        Err(e)       => { problems.push(e); }
+    }
+    for &(name, key, _, _) in SETTINGS.iter() {
+        if let Some(value) = matches.opt_str(name) {
+            if let Err(e) = config.set(key, &value) { problems.push(format!("--{}: {}", name, e)); }
+        }
+    }
+    // The original positional arguments are still accepted.
+    match matches.free.len() {
+        0 => { }
+        3 => {
+            for (key, value) in ["data.style", "data.topic", "data.metadata"].iter().zip(matches.free.iter()) {
+                if let Err(e) = config.set(key, value) { problems.push(e); }
+            }
+        }
+        _ => problems.push("expected three data files: pos-data topic-data metadata".to_string()),
+    }
+    let precompute = matches.opt_present("precompute");
+    if precompute && config.data.neighbors.is_none() {
+        problems.push("--precompute requires data.neighbors".to_string());
+    }
+    if matches.opt_present("recall") && !config.style.index && !config.topic.lsh {
+        problems.push("--recall requires style.index or topic.lsh".to_string());
+    }
+    problems.extend(config.validate());
+    if !problems.is_empty() { fail(&problems); }
+
+    // Tables being replaced are not loaded; they may be out of date
+    // or damaged.
+    let mut load_config = config.clone();
+    if precompute { load_config.data.neighbors = None; }
+    let rec_state = match RecState::new(&load_config) {
+        Ok(rec_state) => rec_state,
+        Err(errors)   => {
+            eprintln!("could not load data:");
+            for e in errors.iter() {
+                eprintln!("  {}", e);
+            }
+            process::exit(1);
+        }
+    };
+
+    if precompute {
+        // Checked above.
+        let path = config.data.neighbors.as_ref().unwrap();
+        println!("computing {} neighbors for each text...", config.data.neighbor_count);
+        let tables = match rec_state.precompute(&config) {
+            Ok(tables) => tables,
+            Err(e)     => {
+                eprintln!("could not precompute: {}", e);
+                process::exit(1);
+            }
+        };
+        if let Err(e) = tables.write(path) {
+            eprintln!("could not write {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        println!("wrote {}", path.display());
+        return;
+    }
+
+    if matches.opt_present("recall") {
+        let k = config.query.default_limit;
+        for (name, recall) in rec_state.recall(RECALL_QUERIES, k) {
+            println!("{} recall@{} over {} texts: {:.3}", name, k, recall.queries, recall.recall);
+            println!("  mean query time: exact {:.2}ms, approximate {:.2}ms",
+                     recall.exact_ms, recall.approximate_ms);
+        }
+        return;
+    }
+
+    if matches.opt_present("check-config") {
+        print!("{}", config);
+        println!("configuration and data ok");
+        return;
+    }
 
     let router = insert_routes! {
         TreeRouter::new() => {
-            "ashurbanipal.web/data/file" => {
+            &config.server.prefix[..] => {
                 "style" => Get: RecQuery::Style,
                 "topic" => Get: RecQuery::Topic,
                 "combination" => Get: RecQuery::Combination,
+                "compare" => Get: RecQuery::Compare,
+                "seeds" => Post: RecQuery::Seeds,
+                "match" => Post: RecQuery::Match,
+                "stats" => Get: RecQuery::Stats,
+                "admin" => {
+                    "features" => Get: RecQuery::Features,
+                },
+                "explain" => {
+                    "style" => Get: RecQuery::ExplainStyle,
+                    "topic" => Get: RecQuery::ExplainTopic,
+                },
                 "lookup" => {
                     Get: RecQuery::TextSearch,
                     ":etext_no" => Get: RecQuery::TextLookup,
@@ -64,15 +235,17 @@ fn main() {
         }
     };
 
-    let rec_state = RecState::new(&args[1], &args[2], &args[3]);
-
     println!("serving...");
 
+    let host = config.socket_addr().unwrap().to_string();
+    let threads = config.server.threads;
+
     let server = Server {
         content_type : content_type!(Application / Json; Charset = Utf8),
-        global       : (rec_state,).into(),
+        global       : (rec_state, config).into(),
         handlers     : router,
-        host         : FromStr::from_str("127.0.0.1:8080").unwrap(),
+        host         : FromStr::from_str(&host).unwrap(),
+        threads      : threads,
         log          : Box::new( rustful::log::StdOut ),
         server       : "ashurbanipal_web(Rust)".to_string(),
         ..Server::default()
@@ -82,3 +255,11 @@ fn main() {
         println!("could not start server: {}", e.description());
     }
 }
+
+/// Report configuration problems on standard error and exit.
+fn fail(problems : &[String]) -> ! {
+    for problem in problems.iter() {
+        eprintln!("configuration error: {}", problem);
+    }
+    process::exit(1);
+}
@@ -1,5 +1,5 @@
 use std::iter::Iterator;
-use std::ops::Index;
+use std::ops::{Index,Range};
 
 #[derive(Debug)]
 pub struct Matrix2<T> {
@@ -24,7 +24,12 @@ impl<T: Clone> Matrix2<T> {
     }
 
     pub fn rows<'m>(&'m self) -> RowsIter<'m,T> {
-        RowsIter { matrix: self, cur: 0 }
+        self.rows_range(0..self.rows)
+    }
+
+    pub fn rows_range<'m>(&'m self, range: Range<usize>) -> RowsIter<'m,T> {
+        assert!(range.end <= self.rows);
+        RowsIter { matrix: self, cur: range.start * self.cols, end: range.end * self.cols }
     }
 }
 
@@ -41,13 +46,14 @@ impl<T> Index<usize> for Matrix2<T> {
 pub struct RowsIter<'m,T: 'm> {
     matrix: &'m Matrix2<T>,
     cur: usize,
+    end: usize,
 }
 
 impl<'m,T> Iterator for RowsIter<'m,T> {
     type Item = &'m [T];
 
     fn next(&mut self) -> Option<&'m [T]> {
-        if self.cur >= self.matrix.data.len() { None }
+        if self.cur >= self.end { None }
         else {
             let cols = self.matrix.cols;
             let offset = self.cur;
@@ -57,7 +63,7 @@ impl<'m,T> Iterator for RowsIter<'m,T> {
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let size = self.matrix.rows - (self.cur / self.matrix.cols);
+        let size = self.end.saturating_sub(self.cur) / self.matrix.cols;
         (size, Some(size))
     }
 }
@@ -73,3 +79,13 @@ fn test1() {
     }
     assert_eq!(x.rows().count(), 2);
 }
+
+#[test]
+fn test2() {
+    let vec = vec!( vec!(1,2), vec!(3,4), vec!(5,6), vec!(7,8) );
+    let x = Matrix2::new(&vec);
+    let rows: Vec<&[i32]> = x.rows_range(1..3).collect();
+    assert_eq!(rows, vec!(&[3,4][..], &[5,6][..]));
+    assert_eq!(x.rows_range(1..3).size_hint(), (2, Some(2)));
+    assert_eq!(x.rows_range(2..2).count(), 0);
+}
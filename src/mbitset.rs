@@ -23,6 +23,9 @@
 #![allow(dead_code)]
 
 use std::cmp;
+use std::fmt;
+use std::hash::{Hash,Hasher};
+use std::io::{self,Read,Write};
 use std::iter::FromIterator;
 
 /// Type of elements used to store bits.
@@ -50,10 +53,6 @@ impl MBitSet {
         bs
     }
 
-    pub fn to_string(&self) -> String {
-        format!("MBit ({:?})", self.storage)
-    }
-
     pub fn clear(&mut self) {
         for i in self.storage.iter_mut() { *i = 0; }
     }
@@ -95,6 +94,17 @@ impl MBitSet {
         }
     }
 
+    /// Remove b from the set, returning true if it was present.
+    pub fn remove(&mut self, b : usize) -> bool {
+        if self.contains(b) {
+            let (elt,bit) = elt_pair(b);
+            self.storage[elt] &= !bit;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn and(&mut self, other : &MBitSet) -> &mut Self {
         let len = cmp::min(self.storage.len(), other.storage.len());
         for i in 0..len {
@@ -116,6 +126,37 @@ impl MBitSet {
         self
     }
 
+    /// Keep the members of exactly one of the sets.
+    pub fn xor(&mut self, other : &MBitSet) -> &mut Self {
+        if other.storage.len() > self.storage.len() {
+            self.extend( other.storage.len() );
+        }
+        for i in 0..other.storage.len() {
+            self.storage[i] ^= other.storage[i];
+        }
+        self
+    }
+
+    /// Remove the members of other.
+    pub fn difference(&mut self, other : &MBitSet) -> &mut Self {
+        let len = cmp::min(self.storage.len(), other.storage.len());
+        for i in 0..len {
+            self.storage[i] &= !other.storage[i];
+        }
+        self
+    }
+
+    /// True if every member of this set is a member of other.
+    pub fn is_subset(&self, other : &MBitSet) -> bool {
+        self.storage.iter().enumerate()
+            .all( |(i,&e)| e & !other.storage.get(i).cloned().unwrap_or(0) == 0 )
+    }
+
+    /// True if the sets have no members in common.
+    pub fn is_disjoint(&self, other : &MBitSet) -> bool {
+        self.storage.iter().zip(other.storage.iter()).all( |(a,b)| a & b == 0 )
+    }
+
     pub fn cardinality(&self) -> usize {
         self.storage.iter()
             .fold(0, |acc,i| {
@@ -129,6 +170,83 @@ impl MBitSet {
             })
     }
 
+    /// The number of members of both sets.
+    pub fn intersection_cardinality(&self, other : &MBitSet) -> usize {
+        self.storage.iter().zip(other.storage.iter())
+            .fold(0, |acc,(a,b)| acc + (a & b).count_ones() as usize)
+    }
+
+    /// The number of members of either set.
+    pub fn union_cardinality(&self, other : &MBitSet) -> usize {
+        let (short, long) = if self.storage.len() < other.storage.len() { (self, other) } else { (other, self) };
+        let common = short.storage.iter().zip(long.storage.iter())
+            .fold(0, |acc,(a,b)| acc + (a | b).count_ones() as usize);
+        long.storage[short.storage.len()..].iter()
+            .fold(common, |acc,a| acc + a.count_ones() as usize)
+    }
+
+    /// The Jaccard similarity of the sets: the size of their
+    /// intersection divided by the size of their union, computed in
+    /// one pass. Two empty sets have a similarity of 0.
+    pub fn jaccard(&self, other : &MBitSet) -> f64 {
+        let (short, long) = if self.storage.len() < other.storage.len() { (self, other) } else { (other, self) };
+        let (intersection, union) = short.storage.iter().zip(long.storage.iter())
+            .fold((0, 0), |(i,u),(a,b)| (i + (a & b).count_ones() as usize, u + (a | b).count_ones() as usize));
+        let union = long.storage[short.storage.len()..].iter()
+            .fold(union, |acc,a| acc + a.count_ones() as usize);
+        if union == 0 { 0.0 } else { intersection as f64 / union as f64 }
+    }
+
+    /// Memory allocated to store the bits, in bytes.
+    pub fn storage_bytes(&self) -> usize {
+        self.storage.capacity() * ESIZE / 8
+    }
+
+    /// Write the set in a compact binary form: the number of 64-bit
+    /// elements, as a little-endian u32, followed by the elements,
+    /// little-endian. Trailing empty elements are not written.
+    pub fn write_to<W : Write>(&self, w : &mut W) -> io::Result<()> {
+        let storage = self.trimmed();
+        if storage.len() > u32::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bit set too large"));
+        }
+        let len = storage.len() as u32;
+        try!(w.write_all(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]));
+        for &e in storage.iter() {
+            let mut bytes = [0u8; 8];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = (e >> (8 * i)) as u8;
+            }
+            try!(w.write_all(&bytes));
+        }
+        Ok(())
+    }
+
+    /// Read a set written by `write_to`.
+    pub fn read_from<R : Read>(r : &mut R) -> io::Result<MBitSet> {
+        let mut len = [0u8; 4];
+        try!(r.read_exact(&mut len));
+        let len = len.iter().rev().fold(0usize, |acc,&b| acc << 8 | b as usize);
+        let mut bs = MBitSet::new();
+        for _ in 0..len {
+            let mut bytes = [0u8; 8];
+            try!(r.read_exact(&mut bytes));
+            bs.storage.push(bytes.iter().rev().fold(0, |acc,&b| acc << 8 | b as Elt));
+        }
+        Ok(bs)
+    }
+
+    /// The storage, without trailing empty elements.
+    fn trimmed(&self) -> &[Elt] {
+        let len = self.storage.iter().rposition( |&e| e != 0 ).map( |i| i + 1 ).unwrap_or(0);
+        &self.storage[..len]
+    }
+
+    /// Iterate over the members of the set, in increasing order.
+    pub fn iter(&self) -> Iter {
+        Iter { storage : &self.storage, elt : 0, bits : self.storage.first().cloned().unwrap_or(0) }
+    }
+
     fn extend(&mut self, elt : usize) {
         let len = self.storage.len();
         self.storage.reserve(elt - len);
@@ -146,6 +264,64 @@ impl FromIterator<usize> for MBitSet {
     }
 }
 
+/// Sets are equal if they have the same members, however much
+/// storage each has.
+impl PartialEq for MBitSet {
+    fn eq(&self, other : &MBitSet) -> bool {
+        self.trimmed() == other.trimmed()
+    }
+}
+
+impl Eq for MBitSet { }
+
+impl Hash for MBitSet {
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        self.trimmed().hash(state);
+    }
+}
+
+impl fmt::Debug for MBitSet {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Display the members, as `{1, 2, 3}`.
+impl fmt::Display for MBitSet {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{{"));
+        for (i, b) in self.iter().enumerate() {
+            if i > 0 { try!(write!(f, ", ")); }
+            try!(write!(f, "{}", b));
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Iterator over the members of an MBitSet.
+pub struct Iter<'a> {
+    storage : &'a [Elt],
+    /// Index of the current element.
+    elt     : usize,
+    /// Bits of the current element not yet returned.
+    bits    : Elt,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.bits == 0 {
+            self.elt += 1;
+            if self.elt >= self.storage.len() { return None; }
+            self.bits = self.storage[self.elt];
+        }
+        let bit = self.bits.trailing_zeros() as usize;
+        self.bits &= self.bits - 1;
+        Some(self.elt * ESIZE + bit)
+    }
+}
+
 fn elt_pair(b : usize) -> (usize, Elt) { (b / ESIZE, 1 << (b % ESIZE)) }
 
 #[test]
@@ -219,3 +395,119 @@ fn t7() {
     assert!(v2.contains(3));
     assert!(v2.contains(128));
 }
+
+#[test]
+fn t8() {
+    let v1 : MBitSet = [1usize,2,3,64,128].iter().map(|&x| x).collect();
+    assert_eq!(v1.iter().collect::<Vec<usize>>(), vec![1,2,3,64,128]);
+    assert_eq!(MBitSet::new().iter().count(), 0);
+    assert_eq!(MBitSet::with_capacity(256).iter().count(), 0);
+}
+
+#[test]
+fn t9() {
+    let v1 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    let v2 : MBitSet = [2usize,3,4].iter().map(|&x| x).collect();
+    assert_eq!(v1.intersection_cardinality(&v2), 2);
+    assert_eq!(v2.intersection_cardinality(&v1), 2);
+    assert_eq!(v1.union_cardinality(&v2), 5);
+    assert_eq!(v2.union_cardinality(&v1), 5);
+    assert_eq!(v1.jaccard(&v2), 0.4);
+    assert_eq!(v2.jaccard(&v1), 0.4);
+    assert_eq!(v1.jaccard(&v1), 1.0);
+    assert_eq!(MBitSet::new().jaccard(&MBitSet::with_capacity(128)), 0.0);
+}
+
+#[test]
+fn t10() {
+    // The fused functions agree with and, or and cardinality.
+    let v1 : MBitSet = (0..300).filter( |x| x % 3 == 0 ).collect();
+    let v2 : MBitSet = (100..200).filter( |x| x % 2 == 0 ).collect();
+    let mut intersection = MBitSet::new();
+    let mut union = MBitSet::new();
+    assert_eq!(v1.intersection_cardinality(&v2), intersection.set(&v1).and(&v2).cardinality());
+    assert_eq!(v1.union_cardinality(&v2), union.set(&v1).or(&v2).cardinality());
+}
+
+#[test]
+fn t11() {
+    let mut v1 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    assert!(v1.remove(2));
+    assert!(!v1.remove(2));
+    assert!(!v1.remove(1000));
+    assert!(v1.contains(1));
+    assert!(!v1.contains(2));
+    assert!(v1.contains(3));
+    assert!(v1.contains(128));
+}
+
+#[test]
+fn t12() {
+    let v1 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    let mut v2 : MBitSet = [2usize,4].iter().map(|&x| x).collect();
+    v2.xor(&v1);
+    assert!(v2.contains(1));
+    assert!(!v2.contains(2));
+    assert!(v2.contains(3));
+    assert!(v2.contains(4));
+    assert!(v2.contains(128));
+}
+
+#[test]
+fn t13() {
+    let v1 : MBitSet = [2usize,4].iter().map(|&x| x).collect();
+    let mut v2 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    v2.difference(&v1);
+    assert!(v2.contains(1));
+    assert!(!v2.contains(2));
+    assert!(v2.contains(3));
+    assert!(!v2.contains(4));
+    assert!(v2.contains(128));
+}
+
+#[test]
+fn t14() {
+    let v1 : MBitSet = [1usize,3].iter().map(|&x| x).collect();
+    let v2 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    let v3 : MBitSet = [4usize,129].iter().map(|&x| x).collect();
+    assert!(v1.is_subset(&v2));
+    assert!(!v2.is_subset(&v1));
+    assert!(MBitSet::with_capacity(256).is_subset(&v1));
+    assert!(v1.is_disjoint(&v3));
+    assert!(v2.is_disjoint(&v3));
+    assert!(!v1.is_disjoint(&v2));
+}
+
+#[test]
+fn t15() {
+    use std::collections::HashSet;
+    let v1 : MBitSet = [1usize,2,3].iter().map(|&x| x).collect();
+    let mut v2 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    assert!(v1 != v2);
+    v2.remove(128);
+    assert_eq!(v1, v2);
+    let mut set = HashSet::new();
+    set.insert(v1);
+    assert!(set.contains(&v2));
+}
+
+#[test]
+fn t16() {
+    let v1 : MBitSet = [1usize,2,3,128].iter().map(|&x| x).collect();
+    assert_eq!(format!("{}", v1), "{1, 2, 3, 128}");
+    assert_eq!(format!("{:?}", v1), "{1, 2, 3, 128}");
+    assert_eq!(format!("{}", MBitSet::new()), "{}");
+}
+
+#[test]
+fn t17() {
+    let mut v1 : MBitSet = [1usize,2,3,128,200].iter().map(|&x| x).collect();
+    v1.remove(200);
+    let mut bytes = Vec::new();
+    v1.write_to(&mut bytes).unwrap();
+    // Four elements, the last of them empty, are written as three.
+    assert_eq!(bytes.len(), 4 + 3 * 8);
+    let v2 = MBitSet::read_from(&mut &bytes[..]).unwrap();
+    assert_eq!(v1, v2);
+    assert!(MBitSet::read_from(&mut &bytes[..10]).is_err());
+}
@@ -20,14 +20,19 @@
  * 02110-1301 USA.
  */
 
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map;
 use std::fs::File;
 use std::io::{BufRead,BufReader};
 use std::path::Path;
 
+use error::{LoadError,parse_field};
 use recommendation::{Etext,Score};
 
+/// Number of tab-separated fields on each line of the metadata file.
+const FIELDS: usize = 10;
+
 #[derive(RustcEncodable)]
 pub struct Text {
     pub etext_no:          Etext,
@@ -57,6 +62,8 @@ pub struct TextRef<'a> {
     pub notes:             &'a str,
     pub copyright_status:  &'a str,
     pub score:             Option<Score>,
+    /// Sources contributing to a combined score.
+    pub signals:           Option<Vec<&'static str>>,
 }
 
 impl Text {
@@ -73,42 +80,113 @@ impl Text {
             notes:             &self.notes,
             copyright_status:  &self.copyright_status,
             score:             Some(score),
+            signals:           None,
         }
     }
 }
 
+/// Conditions on the metadata of recommended texts. Comparisons of
+/// text are case-insensitive; absent conditions match every text.
+#[derive(Default)]
+pub struct Filter {
+    pub language:          Option<String>,
+    pub copyright_status:  Option<String>,
+    /// Prefix of the Library of Congress classification.
+    pub loc_class:         Option<String>,
+    /// Earliest release date, as YYYY, YYYY-MM or YYYY-MM-DD.
+    pub released_from:     Option<String>,
+    /// Latest release date, as YYYY, YYYY-MM or YYYY-MM-DD.
+    pub released_to:       Option<String>,
+    /// Part of the author's name.
+    pub author:            Option<String>,
+    /// Part of the name of an author whose texts are excluded.
+    pub exclude_author:    Option<String>,
+}
+
+impl Filter {
+    /// True if there are no conditions.
+    pub fn is_empty(&self) -> bool {
+        [&self.language, &self.copyright_status, &self.loc_class, &self.released_from,
+         &self.released_to, &self.author, &self.exclude_author].iter().all( |c| c.is_none() )
+    }
+
+    /// True if the text meets every condition.
+    pub fn matches(&self, text: &Text) -> bool {
+        fn lower(s: &str) -> String { s.to_lowercase() }
+        let author = lower(&text.author);
+        // Dates are compared as strings, up to the length of the bound,
+        // so that "1900" matches every date in that year.
+        let date = text.release_date.as_bytes();
+        let before = |bound: &String| date[..cmp::min(date.len(), bound.len())] <= *bound.as_bytes();
+        self.language.as_ref().map_or(true, |l| lower(&text.language) == lower(l))
+            && self.copyright_status.as_ref().map_or(true, |c| lower(&text.copyright_status) == lower(c))
+            && self.loc_class.as_ref().map_or(true, |c| lower(&text.loc_class).starts_with(&lower(c)))
+            && self.released_from.as_ref().map_or(true, |d| !date.is_empty() && date >= d.as_bytes())
+            && self.released_to.as_ref().map_or(true, |d| !date.is_empty() && before(d))
+            && self.author.as_ref().map_or(true, |a| author.contains(&lower(a)))
+            && self.exclude_author.as_ref().map_or(true, |a| !author.contains(&lower(a)))
+    }
+}
+
+/// True if `date` is a date bound accepted by a `Filter`: YYYY,
+/// YYYY-MM or YYYY-MM-DD.
+pub fn valid_date(date: &str) -> bool {
+    let parts: Vec<&str> = date.split('-').collect();
+    let number = |part: &str, digits: usize, max: u32| {
+        part.len() == digits && part.bytes().all( |b| b >= b'0' && b <= b'9' )
+            && part.parse::<u32>().map_or(false, |n| n >= 1 && n <= max)
+    };
+    parts.len() <= 3
+        && number(parts[0], 4, 9999)
+        && parts.get(1).map_or(true, |m| number(m, 2, 12))
+        && parts.get(2).map_or(true, |d| number(d, 2, 31))
+}
+
 pub struct Metadata {
     metadata:     HashMap<Etext,Text>,
 }
 
 impl Metadata {
-    pub fn read<P : AsRef<Path>>(path:P) -> Metadata {
-        let texts: HashMap<Etext,Text> = 
-            BufReader::new( panic_unless!("metadata", result: File::open(path)) ).lines()
+    /// Read the metadata file, which has a header line followed by
+    /// tab-separated lines of the fields of `Text`, in order.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `LoadError` if the file cannot be read, a line has
+    /// too few fields, or the etext number is not an integer.
+    pub fn read<P : AsRef<Path>>(path:P) -> Result<Metadata,LoadError> {
+        let path = path.as_ref();
+        let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+        let mut texts: HashMap<Etext,Text> = HashMap::new();
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let lineno = i + 1;
+            let line   = try!(line.map_err( |e| LoadError::at(path, lineno, None, e) ));
             // file header
-            .skip(1)
-            .map( |line| {
-                let line  = panic_unless!("metadata", result: line);
-                let elements: Vec<&str> = line.split('\t').collect();
-                let etext_no: Etext = panic_unless!("etext number",
-                                                    result: elements[0].parse());
-                let t = Text {
-                      etext_no:          etext_no,
-                      link:              elements[1].to_string(),
-                      title:             elements[2].to_string(),
-                      author:            elements[3].to_string(),
-                      subject:           elements[4].to_string(),
-                      language:          elements[5].to_string(),
-                      release_date:      elements[6].to_string(),
-                      loc_class:         elements[7].to_string(),
-                      notes:             elements[8].to_string(),
-                      copyright_status:  elements[9].to_string(),
-                      score:             None,
-                };
-                ( etext_no, t )
-            } ).collect();
-
-        Metadata { metadata: texts, }
+            if lineno == 1 { continue; }
+            let elements: Vec<&str> = line.split('\t').collect();
+            if elements.len() < FIELDS {
+                return Err(LoadError::at(path, lineno, Some(elements.len() + 1),
+                                         format!("expected {} fields, found {}", FIELDS, elements.len())));
+            }
+            let etext_no: Etext = try!(parse_field(path, lineno, 1, Some(elements[0]), "etext number"));
+            let t = Text {
+                  etext_no:          etext_no,
+                  link:              elements[1].to_string(),
+                  title:             elements[2].to_string(),
+                  author:            elements[3].to_string(),
+                  subject:           elements[4].to_string(),
+                  language:          elements[5].to_string(),
+                  release_date:      elements[6].to_string(),
+                  loc_class:         elements[7].to_string(),
+                  notes:             elements[8].to_string(),
+                  copyright_status:  elements[9].to_string(),
+                  score:             None,
+            };
+            texts.insert(etext_no, t);
+        }
+
+        Ok(Metadata { metadata: texts, })
     }
 
     pub fn get(&self, etext_no: Etext) -> Option<&Text> {
@@ -119,6 +197,17 @@ impl Metadata {
         self.metadata.iter()
     }
 
+    /// True if the text meets the filter's conditions. Texts without
+    /// metadata only meet those of an empty filter.
+    pub fn allows(&self, etext_no: Etext, filter: &Filter) -> bool {
+        filter.is_empty() || self.get(etext_no).map_or(false, |text| filter.matches(text))
+    }
+
+    /// Keep the results whose texts meet the filter's conditions.
+    pub fn filter(&self, results: Vec<(Etext,Score)>, filter: &Filter) -> Vec<(Etext,Score)> {
+        results.into_iter().filter( |&(e,_)| self.allows(e, filter) ).collect()
+    }
+
 
     pub fn add_metadata<'a>(&'a self, rows: &Vec<(Etext,Score)>, start: usize, limit: usize) -> Vec<TextRef<'a>> {
         rows.iter()
@@ -134,3 +223,50 @@ impl Metadata {
             .collect()
     }
 }
+
+#[cfg(test)]
+fn text(etext_no: Etext, author: &str, language: &str, release_date: &str, loc_class: &str) -> Text {
+    Text {
+        etext_no:          etext_no,
+        link:              String::new(),
+        title:             String::new(),
+        author:            author.to_string(),
+        subject:           String::new(),
+        language:          language.to_string(),
+        release_date:      release_date.to_string(),
+        loc_class:         loc_class.to_string(),
+        notes:             String::new(),
+        copyright_status:  "Public domain in the USA.".to_string(),
+        score:             None,
+    }
+}
+
+#[test]
+fn test_filter() {
+    let texts: HashMap<Etext,Text> = vec![
+        text(1, "Wilde, Oscar", "English", "1997-01-01", "PR"),
+        text(2, "Kivi, Aleksis", "Finnish", "2004-05-01", "PH"),
+        text(3, "Doyle, Arthur Conan", "English", "2005-03-10", "PR"),
+        text(4, "Austen, Jane", "English", "", "PR"),
+    ].into_iter().map( |t| (t.etext_no, t) ).collect();
+    let metadata = Metadata { metadata: texts };
+    let results = vec![(1, 0.1), (2, 0.2), (3, 0.3), (4, 0.4), (5, 0.5)];
+    let filtered = |filter: Filter| metadata.filter(results.clone(), &filter).iter().map( |&(e,_)| e ).collect::<Vec<_>>();
+    assert_eq!(filtered(Filter::default()), vec![1, 2, 3, 4, 5]);
+    assert_eq!(filtered(Filter { language: Some("english".to_string()), ..Filter::default() }), vec![1, 3, 4]);
+    assert_eq!(filtered(Filter { loc_class: Some("P".to_string()), exclude_author: Some("wilde".to_string()),
+                                 ..Filter::default() }), vec![2, 3, 4]);
+    assert_eq!(filtered(Filter { released_from: Some("2000".to_string()), released_to: Some("2004".to_string()),
+                                 ..Filter::default() }), vec![2]);
+    assert_eq!(filtered(Filter { author: Some("Doyle".to_string()), ..Filter::default() }), vec![3]);
+}
+
+#[test]
+fn test_valid_date() {
+    for date in ["1900", "2004-05", "2004-05-01"].iter() {
+        assert!(valid_date(date), "{}", date);
+    }
+    for date in ["", "abc", "2020-1-5", "2020-13", "2020-05-32", "2020-05-01-01", "20200", "2020-"].iter() {
+        assert!(!valid_date(date), "{}", date);
+    }
+}
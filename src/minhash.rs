@@ -0,0 +1,149 @@
+//! MinHash signatures and locality-sensitive hashing of noun sets.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::collections::HashMap;
+use std::u32;
+
+use nounset::NounSet;
+use parallel;
+
+/// Increment of the SplitMix64 generator.
+const GOLDEN_GAMMA : u64 = 0x9e3779b97f4a7c15;
+
+/// Locality-sensitive hash index over a collection of sets.
+///
+/// Each set gets a MinHash signature of `bands * rows` values; the
+/// probability that two sets agree in any one value is their Jaccard
+/// similarity. The signature is divided into bands of `rows` values,
+/// and sets agreeing in every value of some band are candidates for
+/// each other. More rows per band makes candidates more similar;
+/// more bands makes it more likely that similar sets are found.
+pub struct Lsh {
+    bands      : usize,
+    rows       : usize,
+    /// Signatures of the sets, concatenated.
+    signatures : Vec<u32>,
+    /// For each band, the sets sharing each bucket.
+    buckets    : Vec<HashMap<u64,Vec<usize>>>,
+}
+
+impl Lsh {
+
+    /// Build an index over the sets, computing signatures on up to
+    /// `workers` threads.
+    ///
+    /// # Panics
+    ///
+    /// `bands` and `rows` must be non-zero.
+    pub fn new<S : NounSet>(sets : &[S], bands : usize, rows : usize, workers : usize) -> Lsh {
+        assert!(bands > 0 && rows > 0);
+        let hashes = bands * rows;
+        let signatures = parallel::scan(workers, sets.len(), |range| {
+            let mut signatures = Vec::with_capacity(range.len() * hashes);
+            for i in range {
+                signatures.extend(signature(&sets[i], hashes));
+            }
+            signatures
+        });
+        let mut buckets = vec![HashMap::new(); bands];
+        for i in 0..sets.len() {
+            let signature = &signatures[i * hashes..(i + 1) * hashes];
+            for (band, bucket) in buckets.iter_mut().enumerate() {
+                bucket.entry(band_key(&signature[band * rows..(band + 1) * rows]))
+                    .or_insert_with(Vec::new)
+                    .push(i);
+            }
+        }
+        Lsh { bands : bands, rows : rows, signatures : signatures, buckets : buckets }
+    }
+
+    /// The sets sharing a bucket with set `index`, including itself,
+    /// in increasing order.
+    pub fn candidates(&self, index : usize) -> Vec<usize> {
+        let hashes = self.bands * self.rows;
+        let signature = &self.signatures[index * hashes..(index + 1) * hashes];
+        let mut candidates = Vec::new();
+        for (band, bucket) in self.buckets.iter().enumerate() {
+            if let Some(members) = bucket.get(&band_key(&signature[band * self.rows..(band + 1) * self.rows])) {
+                candidates.extend(members.iter().cloned());
+            }
+        }
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+/// The MinHash signature of a set: for each of `hashes` hash
+/// functions, the least hash of any member.
+fn signature<S : NounSet>(set : &S, hashes : usize) -> Vec<u32> {
+    let mut signature = vec![u32::MAX; hashes];
+    for member in set.members() {
+        // Hash function i is the i-th output of a SplitMix64
+        // generator seeded by the member.
+        let mut state = mix(member as u64 ^ GOLDEN_GAMMA);
+        for min in signature.iter_mut() {
+            state = state.wrapping_add(GOLDEN_GAMMA);
+            let hash = mix(state) as u32;
+            if hash < *min { *min = hash; }
+        }
+    }
+    signature
+}
+
+/// Hash the values of one band of a signature.
+fn band_key(values : &[u32]) -> u64 {
+    values.iter().fold(0, |key, &v| mix(key ^ v as u64))
+}
+
+/// The SplitMix64 finalizer, which scrambles the bits of `x`.
+fn mix(x : u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+use mbitset::MBitSet;
+
+#[test]
+fn test_signature() {
+    let a : MBitSet = (0..100).collect();
+    let b : MBitSet = (50..150).collect();
+    let (sa, sb) = (signature(&a, 1000), signature(&b, 1000));
+    assert_eq!(sa, signature(&a, 1000));
+    // The Jaccard similarity of a and b is 1/3.
+    let agree = sa.iter().zip(sb.iter()).filter( |&(x,y)| x == y ).count();
+    assert!(agree > 250 && agree < 420, "agreement {}", agree);
+    assert_eq!(signature(&MBitSet::new(), 3), vec![u32::MAX; 3]);
+}
+
+#[test]
+fn test_candidates() {
+    let sets : Vec<MBitSet> = vec![(0..100).collect(),
+                                   (1..100).collect(),
+                                   (1000..1100).collect(),
+                                   (0..100).collect()];
+    let lsh = Lsh::new(&sets, 20, 4, 1);
+    assert_eq!(lsh.candidates(0), vec![0, 1, 3]);
+    assert_eq!(lsh.candidates(2), vec![2]);
+}
@@ -0,0 +1,412 @@
+//! Precomputed nearest-neighbor tables.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self,BufReader,BufWriter,Read,Write};
+use std::path::Path;
+
+use combination::{Combination,Contributors};
+use error::LoadError;
+use recommendation::{Etext,Recommendation,Score,select_top};
+
+/// Identifies a neighbor table file, and its format version.
+const MAGIC : &'static [u8; 8] = b"ASHNN003";
+
+/// Named settings and data fingerprints from which tables were built.
+pub type Settings = Vec<(String,String)>;
+
+/// The best recommendations for each etext, computed ahead of time.
+pub struct Neighbors {
+    /// Number of neighbors kept for each etext.
+    n            : usize,
+    /// For each etext, the total number of results and the best
+    /// results, sorted by score, with the sources contributing to
+    /// each.
+    rows         : HashMap<Etext,(usize,Vec<(Etext,Score,Contributors)>)>,
+    /// True if the contributing sources were recorded.
+    contributors : bool,
+}
+
+impl Neighbors {
+
+    /// Compute the best `n` recommendations of `r` for each of the
+    /// etexts. Etexts that `r` cannot score are left out.
+    pub fn compute(r : &Recommendation, etexts : &[Etext], n : usize) -> Neighbors {
+        let mut rows = HashMap::with_capacity(etexts.len());
+        for &etext_no in etexts.iter() {
+            if let Some((count, results)) = r.top_k(etext_no, n, 0) {
+                rows.insert(etext_no, (count, results.into_iter().map( |(e,s)| (e,s,0) ).collect()));
+            }
+        }
+        Neighbors { n : n, rows : rows, contributors : false }
+    }
+
+    /// As `compute`, but recording the sources contributing to each
+    /// combined result.
+    pub fn compute_combined(combination : &Combination, etexts : &[Etext], n : usize) -> Neighbors {
+        let mut rows = HashMap::with_capacity(etexts.len());
+        for &etext_no in etexts.iter() {
+            if let Some(results) = combination.combined_results(etext_no) {
+                let scores : Vec<(Etext,Score)> = results.iter().map( |&(e,s,_)| (e,s) ).collect();
+                let best = select_top(&scores, n, 0).into_iter()
+                    .map( |(e,s)| {
+                        // The combined results are sorted by etext number.
+                        let i = panic_unless!("combined result", result: results.binary_search_by( |&(f,_,_)| f.cmp(&e) ));
+                        (e, s, results[i].2)
+                    })
+                    .collect();
+                rows.insert(etext_no, (results.len(), best));
+            }
+        }
+        Neighbors { n : n, rows : rows, contributors : true }
+    }
+
+    /// True if this table holds the results from `offset` to
+    /// `offset + k`.
+    pub fn covers(&self, k : usize, offset : usize) -> bool {
+        offset.saturating_add(k) <= self.n
+    }
+
+    /// As `top_k`, with the sources contributing to each result, if
+    /// they were recorded.
+    pub fn top_k_contributors(&self, etext_no : Etext, k : usize, offset : usize)
+                              -> Option<(usize,Vec<(Etext,Score,Contributors)>)> {
+        if !self.contributors { return None; }
+        self.rows.get(&etext_no).map( |&(count, ref neighbors)| {
+            (count, neighbors.iter().cloned().skip(offset).take(k).collect())
+        })
+    }
+}
+
+impl Recommendation for Neighbors {
+
+    /// Return the stored neighbors of the etext, sorted by etext
+    /// number. Unlike a live recommendation, this is not every etext.
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.rows.get(&etext_no).map( |&(_, ref neighbors)| {
+            let mut results : Vec<(Etext,Score)> = neighbors.iter().map( |&(e,s,_)| (e,s) ).collect();
+            results.sort_by( |l,r| l.0.cmp(&r.0) );
+            results
+        })
+    }
+
+    /// Return the stored neighbors of the etext, along with the
+    /// total number of results when they were computed.
+    fn top_k(&self, etext_no : Etext, k : usize, offset : usize) -> Option<(usize,Vec<(Etext,Score)>)> {
+        self.rows.get(&etext_no).map( |&(count, ref neighbors)| {
+            (count, neighbors.iter().skip(offset).take(k).map( |&(e,s,_)| (e,s) ).collect())
+        })
+    }
+}
+
+/// A set of named neighbor tables, such as "style" and "topic", with
+/// the settings they were built from.
+pub struct NeighborTables {
+    settings : Settings,
+    tables   : Vec<(String,Neighbors)>,
+}
+
+impl NeighborTables {
+
+    pub fn new(settings : Settings) -> NeighborTables {
+        NeighborTables { settings : settings, tables : Vec::new() }
+    }
+
+    pub fn add(&mut self, name : &str, neighbors : Neighbors) {
+        self.tables.push((name.to_string(), neighbors));
+    }
+
+    pub fn get(&self, name : &str) -> Option<&Neighbors> {
+        self.tables.iter().find( |&&(ref n,_)| n == name ).map( |&(_, ref t)| t )
+    }
+
+    /// Read a neighbor table file.
+    ///
+    /// The file is little-endian binary, of the form
+    ///
+    /// ```
+    /// magic                    8 bytes, "ASHNN003"
+    /// number of settings       u32
+    /// for each setting:
+    ///   name length, name      u32, UTF-8 bytes
+    ///   value length, value    u32, UTF-8 bytes
+    /// number of tables         u32
+    /// for each table:
+    ///   name length, name      u32, UTF-8 bytes
+    ///   neighbors per etext    u32
+    ///   contributors recorded  u32, 0 or 1
+    ///   number of etexts       u32
+    ///   for each etext:
+    ///     etext number         u32
+    ///     number of results    u32
+    ///     number of neighbors  u32
+    ///     for each neighbor:
+    ///       etext number       u32
+    ///       score              f32
+    ///       contributors       2 u32, low half first, if recorded
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns a `LoadError` if the file cannot be read, is not in
+    /// this format, or was built from settings other than `settings`;
+    /// the tables would no longer match live recommendations.
+    pub fn read<P : AsRef<Path>>(path : P, settings : &[(String,String)]) -> Result<NeighborTables,LoadError> {
+        let path = path.as_ref();
+        let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+        let tables = try!(NeighborTables::read_from(&mut BufReader::new(file)).map_err( |e| LoadError::new(path, e) ));
+        let changed = changes(&tables.settings, settings);
+        if !changed.is_empty() {
+            return Err(LoadError::new(path, format!("built with other settings ({}); run --precompute",
+                                                    changed.join(", "))));
+        }
+        Ok(tables)
+    }
+
+    /// Counts read from the file are not trusted to size buffers, so
+    /// that a damaged file is an error rather than a huge allocation.
+    fn read_from<R : Read>(r : &mut R) -> io::Result<NeighborTables> {
+        let mut magic = [0u8; 8];
+        try!(r.read_exact(&mut magic));
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a neighbor table file"));
+        }
+        let mut settings = Vec::new();
+        for _ in 0..try!(read_u32(r)) {
+            let name = try!(read_string(r));
+            settings.push((name, try!(read_string(r))));
+        }
+        let mut tables = NeighborTables::new(settings);
+        for _ in 0..try!(read_u32(r)) {
+            let name = try!(read_string(r));
+            let n = try!(read_u32(r)) as usize;
+            let contributors = try!(read_u32(r)) != 0;
+            let etexts = try!(read_u32(r)) as usize;
+            let mut rows = HashMap::new();
+            for _ in 0..etexts {
+                let etext_no = try!(read_u32(r)) as Etext;
+                let count = try!(read_u32(r)) as usize;
+                let len = try!(read_u32(r)) as usize;
+                let mut neighbors = Vec::new();
+                for _ in 0..len {
+                    let neighbor = try!(read_u32(r)) as Etext;
+                    let score = f32::from_bits(try!(read_u32(r)));
+                    let sources = if contributors {
+                        try!(read_u32(r)) as Contributors | (try!(read_u32(r)) as Contributors) << 32
+                    } else {
+                        0
+                    };
+                    neighbors.push((neighbor, score as Score, sources));
+                }
+                rows.insert(etext_no, (count, neighbors));
+            }
+            tables.add(&name, Neighbors { n : n, rows : rows, contributors : contributors });
+        }
+        Ok(tables)
+    }
+
+    /// Write the tables to a file, in the format described for
+    /// `read`.
+    pub fn write<P : AsRef<Path>>(&self, path : P) -> io::Result<()> {
+        let mut w = BufWriter::new(try!(File::create(path)));
+        try!(self.write_to(&mut w));
+        w.flush()
+    }
+
+    fn write_to<W : Write>(&self, w : &mut W) -> io::Result<()> {
+        try!(w.write_all(MAGIC));
+        try!(write_u32(w, self.settings.len()));
+        for &(ref name, ref value) in self.settings.iter() {
+            try!(write_string(w, name));
+            try!(write_string(w, value));
+        }
+        try!(write_u32(w, self.tables.len()));
+        for &(ref name, ref table) in self.tables.iter() {
+            try!(write_string(w, name));
+            try!(write_u32(w, table.n));
+            try!(write_u32(w, table.contributors as usize));
+            try!(write_u32(w, table.rows.len()));
+            // Written in etext order, so that the file is reproducible.
+            let mut etexts : Vec<&Etext> = table.rows.keys().collect();
+            etexts.sort();
+            for etext_no in etexts {
+                let &(count, ref neighbors) = &table.rows[etext_no];
+                try!(write_u32(w, *etext_no));
+                try!(write_u32(w, count));
+                try!(write_u32(w, neighbors.len()));
+                for &(neighbor, score, sources) in neighbors.iter() {
+                    try!(write_u32(w, neighbor));
+                    try!(w.write_all(&le_bytes((score as f32).to_bits())));
+                    if table.contributors {
+                        try!(w.write_all(&le_bytes(sources as u32)));
+                        try!(w.write_all(&le_bytes((sources >> 32) as u32)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describe the settings that differ between those of a table and
+/// those now in use.
+fn changes(table : &[(String,String)], current : &[(String,String)]) -> Vec<String> {
+    let lookup = |settings : &[(String,String)], name : &str| {
+        settings.iter().find( |&&(ref n,_)| n == name ).map_or("(unset)".to_string(), |&(_, ref v)| v.clone() )
+    };
+    let mut names : Vec<&String> = table.iter().chain(current.iter()).map( |&(ref n,_)| n ).collect();
+    names.sort();
+    names.dedup();
+    names.into_iter()
+        .map( |name| (name, lookup(table, name), lookup(current, name)) )
+        .filter( |&(_, ref built, ref now)| built != now )
+        .map( |(name, built, now)| format!("{} was {}, now {}", name, built, now) )
+        .collect()
+}
+
+/// A fingerprint of a file's contents: its length and FNV-1a hash.
+pub fn fingerprint(path : &Path) -> Result<String,LoadError> {
+    let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+    let mut hash : u64 = 0xcbf29ce484222325;
+    let mut len : u64 = 0;
+    for byte in BufReader::new(file).bytes() {
+        hash = (hash ^ try!(byte.map_err( |e| LoadError::new(path, e) )) as u64).wrapping_mul(0x100000001b3);
+        len += 1;
+    }
+    Ok(format!("{}:{:016x}", len, hash))
+}
+
+fn le_bytes(x : u32) -> [u8; 4] {
+    [x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]
+}
+
+fn write_u32<W : Write>(w : &mut W, x : usize) -> io::Result<()> {
+    if x > u32::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "value too large for neighbor table"));
+    }
+    w.write_all(&le_bytes(x as u32))
+}
+
+/// Read a length-prefixed UTF-8 string, reading no more than the
+/// file holds whatever the length claims.
+fn read_string<R : Read>(r : &mut R) -> io::Result<String> {
+    let len = try!(read_u32(r)) as u64;
+    let mut bytes = Vec::new();
+    if try!(r.take(len).read_to_end(&mut bytes)) as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated neighbor table file"));
+    }
+    String::from_utf8(bytes).map_err( |_| io::Error::new(io::ErrorKind::InvalidData, "invalid string in neighbor table") )
+}
+
+fn write_string<W : Write>(w : &mut W, s : &str) -> io::Result<()> {
+    try!(write_u32(w, s.len()));
+    w.write_all(s.as_bytes())
+}
+
+fn read_u32<R : Read>(r : &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    try!(r.read_exact(&mut b));
+    Ok((b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+#[cfg(test)]
+use recommendation::Fixed;
+
+#[test]
+fn test_compute() {
+    let source = Fixed(vec![(1, 0.5), (2, 0.25), (3, 0.75), (4, 0.0)]);
+    let neighbors = Neighbors::compute(&source, &[1, 2, 99], 2);
+    assert_eq!(neighbors.top_k(1, 2, 0), Some((4, vec![(4, 0.0), (2, 0.25)])));
+    assert_eq!(neighbors.top_k(1, 5, 1), Some((4, vec![(2, 0.25)])));
+    assert_eq!(neighbors.scored_results(1), Some(vec![(2, 0.25), (4, 0.0)]));
+    assert_eq!(neighbors.top_k(99, 2, 0), None);
+    assert!(neighbors.covers(1, 1));
+    assert!(!neighbors.covers(2, 1));
+}
+
+#[test]
+fn test_round_trip() {
+    let source = Fixed(vec![(1, 0.5), (2, 0.25), (3, 0.75), (4, 0.0)]);
+    let settings = vec![("style.metric".to_string(), "euclidean".to_string())];
+    let mut tables = NeighborTables::new(settings.clone());
+    tables.add("style", Neighbors::compute(&source, &[1, 2, 3, 4], 3));
+    tables.add("topic", Neighbors::compute(&source, &[4], 1));
+    let mut buffer = Vec::new();
+    tables.write_to(&mut buffer).unwrap();
+    let read = NeighborTables::read_from(&mut &buffer[..]).unwrap();
+    assert_eq!(read.settings, settings);
+    let style = read.get("style").unwrap();
+    assert_eq!(style.top_k(3, 3, 0), Some((4, vec![(4, 0.0), (2, 0.25), (1, 0.5)])));
+    assert_eq!(read.get("topic").unwrap().top_k(4, 3, 0), Some((4, vec![(4, 0.0)])));
+    assert!(read.get("combination").is_none());
+    assert!(NeighborTables::read_from(&mut &buffer[1..]).is_err());
+}
+
+#[test]
+fn test_damaged() {
+    let mut tables = NeighborTables::new(Vec::new());
+    tables.add("style", Neighbors::compute(&Fixed(vec![(1, 0.5), (2, 0.25)]), &[1, 2], 2));
+    let mut buffer = Vec::new();
+    tables.write_to(&mut buffer).unwrap();
+    for len in 0..buffer.len() {
+        assert!(NeighborTables::read_from(&mut &buffer[..len]).is_err());
+    }
+    // Huge counts: a name length, then an etext count.
+    let mut huge = buffer[..16].to_vec();
+    huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    assert!(NeighborTables::read_from(&mut &huge[..]).is_err());
+    let mut huge = buffer[..33].to_vec();
+    huge.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    assert!(NeighborTables::read_from(&mut &huge[..]).is_err());
+}
+
+#[test]
+fn test_changes() {
+    let setting = |n : &str, v : &str| (n.to_string(), v.to_string());
+    let built = vec![setting("style.metric", "euclidean"), setting("topic.metric", "jaccard")];
+    assert!(changes(&built, &built).is_empty());
+    let now = vec![setting("style.metric", "cosine"), setting("topic.metric", "jaccard"), setting("style.weights", "none")];
+    assert_eq!(changes(&built, &now), vec!["style.metric was euclidean, now cosine".to_string(),
+                                           "style.weights was (unset), now none".to_string()]);
+}
+
+#[test]
+fn test_contributors() {
+    use combination::{Fusion,Missing};
+    let style = Fixed(vec![(1, 0.5), (2, 0.25), (3, 0.75)]);
+    let topic = Fixed(vec![(1, 0.5), (3, 0.25)]);
+    let fusion = Fusion { missing : Missing::Worst, ..Fusion::default() };
+    let combination = Combination::new(fusion).add("style", &style, 1.0).add("topic", &topic, 1.0);
+    let mut tables = NeighborTables::new(Vec::new());
+    tables.add("combination", Neighbors::compute_combined(&combination, &[1], 3));
+    let mut buffer = Vec::new();
+    tables.write_to(&mut buffer).unwrap();
+    let read = NeighborTables::read_from(&mut &buffer[..]).unwrap();
+    let table = read.get("combination").unwrap();
+    let (count, rows) = table.top_k_contributors(1, 3, 0).unwrap();
+    assert_eq!(count, 3);
+    let sources : Vec<(Etext,Contributors)> = rows.iter().map( |&(e,_,c)| (e,c) ).collect();
+    assert!(sources.contains(&(2, 0b01)) && sources.contains(&(3, 0b11)));
+    assert_eq!(table.top_k(1, 3, 0).map( |(_, r)| r.len() ), Some(3));
+    assert!(Neighbors::compute(&style, &[1], 3).top_k_contributors(1, 3, 0).is_none());
+}
@@ -0,0 +1,108 @@
+//! Common interface of the representations of sets of nouns.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::iter::FromIterator;
+use std::mem;
+use std::str::FromStr;
+
+use mbitset::MBitSet;
+
+/// A set of noun numbers, as used by topic data.
+pub trait NounSet : FromIterator<usize> + Send + Sync {
+    /// True if the noun is in the set.
+    fn contains(&self, noun : usize) -> bool;
+
+    /// The number of nouns in the set.
+    fn cardinality(&self) -> usize;
+
+    /// The number of nouns in both sets.
+    fn intersection_cardinality(&self, other : &Self) -> usize;
+
+    /// The number of nouns in either set.
+    fn union_cardinality(&self, other : &Self) -> usize {
+        self.cardinality() + other.cardinality() - self.intersection_cardinality(other)
+    }
+
+    /// The Jaccard similarity of the sets. Two empty sets have a
+    /// similarity of 0.
+    fn jaccard(&self, other : &Self) -> f64 {
+        let union = self.union_cardinality(other);
+        if union == 0 { 0.0 } else { self.intersection_cardinality(other) as f64 / union as f64 }
+    }
+
+    /// The nouns in the set, in increasing order.
+    fn members(&self) -> Vec<usize>;
+
+    /// Approximate memory used by the set, in bytes.
+    fn bytes(&self) -> usize;
+}
+
+/// Representations of noun sets selectable when loading topic data.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Representation {
+    /// `MBitSet`, a bit for every noun number up to the largest.
+    Dense,
+    /// `RoaringSet`, compressed containers of nearby nouns.
+    Compressed,
+}
+
+impl FromStr for Representation {
+    type Err = ();
+
+    fn from_str(s : &str) -> Result<Representation,()> {
+        match s {
+            "dense"      => Ok(Representation::Dense),
+            "compressed" => Ok(Representation::Compressed),
+            _            => Err(()),
+        }
+    }
+}
+
+impl NounSet for MBitSet {
+    fn contains(&self, noun : usize) -> bool {
+        MBitSet::contains(self, noun)
+    }
+
+    fn cardinality(&self) -> usize {
+        MBitSet::cardinality(self)
+    }
+
+    fn intersection_cardinality(&self, other : &MBitSet) -> usize {
+        MBitSet::intersection_cardinality(self, other)
+    }
+
+    fn union_cardinality(&self, other : &MBitSet) -> usize {
+        MBitSet::union_cardinality(self, other)
+    }
+
+    fn jaccard(&self, other : &MBitSet) -> f64 {
+        MBitSet::jaccard(self, other)
+    }
+
+    fn members(&self) -> Vec<usize> {
+        self.iter().collect()
+    }
+
+    fn bytes(&self) -> usize {
+        mem::size_of::<MBitSet>() + self.storage_bytes()
+    }
+}
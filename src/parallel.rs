@@ -0,0 +1,69 @@
+//! Parallel scans over the rows of recommendation data.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::cmp;
+use std::ops::Range;
+
+use crossbeam;
+
+/// Smallest number of rows worth handing to a separate thread.
+const MIN_CHUNK : usize = 1024;
+
+/// Apply `f` to contiguous chunks of the row indices `0..len`, using
+/// up to `workers` threads, and concatenate the results in row order.
+///
+/// Each call of `f` handles one range of rows and returns one result
+/// per row, so that any scratch space it needs is allocated once per
+/// chunk.
+pub fn scan<T,F>(workers : usize, len : usize, f : F) -> Vec<T>
+    where T : Send, F : Fn(Range<usize>) -> Vec<T> + Sync {
+    let workers = cmp::min(workers, len / MIN_CHUNK);
+    if workers <= 1 {
+        return f(0..len);
+    }
+    let chunk = (len + workers - 1) / workers;
+    let f = &f;
+    crossbeam::scope( |scope| {
+        let handles : Vec<_> = (0..workers)
+            .map( |w| {
+                let range = w * chunk .. cmp::min((w + 1) * chunk, len);
+                scope.spawn( move || f(range) )
+            })
+            .collect();
+        let mut results = Vec::with_capacity(len);
+        for handle in handles {
+            results.extend(handle.join());
+        }
+        results
+    })
+}
+
+#[test]
+fn test_scan() {
+    let f = |range : Range<usize>| range.map( |i| i * 2 ).collect::<Vec<usize>>();
+    let expected : Vec<usize> = (0..10000).map( |i| i * 2 ).collect();
+    assert_eq!(scan(1, 10000, &f), expected);
+    assert_eq!(scan(4, 10000, &f), expected);
+    assert_eq!(scan(7, 10000, &f), expected);
+    assert_eq!(scan(4, 10, &f), vec![0, 2, 4, 6, 8, 10, 12, 14, 16, 18]);
+    assert_eq!(scan(4, 0, &f), vec![]);
+}
@@ -0,0 +1,165 @@
+//! Query-parameter validation.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// A problem with one query parameter.
+#[derive(Clone,Debug,PartialEq,RustcEncodable)]
+pub struct Problem {
+    pub parameter : String,
+    pub message   : String,
+}
+
+/// Validated access to a request's query parameters.
+///
+/// Each accessor records the parameter as known and notes any problem
+/// with its value, so that a request can be rejected with every
+/// problem at once rather than the first one found.
+pub struct Params<'q> {
+    query    : &'q HashMap<String,String>,
+    known    : Vec<String>,
+    problems : Vec<Problem>,
+}
+
+impl<'q> Params<'q> {
+
+    pub fn new(query : &'q HashMap<String,String>) -> Params<'q> {
+        Params { query : query, known : Vec::new(), problems : Vec::new() }
+    }
+
+    /// Get a parameter that must be present and valid. None is only
+    /// returned when a problem has been recorded.
+    pub fn required<T : FromStr>(&mut self, name : &str) -> Option<T> {
+        self.known.push(name.to_string());
+        match self.query.get(name) {
+            Some(s) => self.parse(name, s),
+            None    => {
+                self.problem(name, format!("parameter required: {}", name));
+                None
+            }
+        }
+    }
+
+    /// Get a parameter that may be absent, using `default` if it is
+    /// absent or invalid.
+    pub fn optional<T : FromStr>(&mut self, name : &str, default : T) -> T {
+        self.known.push(name.to_string());
+        match self.query.get(name) {
+            Some(s) => self.parse(name, s).unwrap_or(default),
+            None    => default,
+        }
+    }
+
+    /// Get an optional parameter that must lie between `min` and
+    /// `max`, inclusive.
+    pub fn bounded<T : FromStr + PartialOrd + Display + Copy>(&mut self, name : &str, default : T,
+                                                             min : T, max : T) -> T {
+        let value = self.optional(name, default);
+        // Written this way so that NaN is rejected.
+        if !(value >= min && value <= max) {
+            self.problem(name, format!("{} must be between {} and {}: {}", name, min, max, value));
+            default
+        } else {
+            value
+        }
+    }
+
+    /// Finish validation, returning every problem found. If `strict`,
+    /// parameters no accessor asked for are also problems.
+    pub fn finish(mut self, strict : bool) -> Result<(),Vec<Problem>> {
+        if strict {
+            let mut unknown : Vec<&String> = self.query.keys()
+                .filter( |k| !self.known.contains(k) )
+                .collect();
+            unknown.sort();
+            for name in unknown {
+                self.problems.push(Problem {
+                    parameter : name.clone(),
+                    message   : format!("unknown parameter: {}", name),
+                });
+            }
+        }
+        if self.problems.is_empty() { Ok(()) } else { Err(self.problems) }
+    }
+
+    fn parse<T : FromStr>(&mut self, name : &str, s : &str) -> Option<T> {
+        let value = s.parse().ok();
+        if value.is_none() {
+            self.problem(name, format!("invalid value for {}: {}", name, s));
+        }
+        value
+    }
+
+    /// Record a problem found by the caller, such as an invalid
+    /// combination of parameters.
+    pub fn problem(&mut self, name : &str, message : String) {
+        self.problems.push(Problem { parameter : name.to_string(), message : message });
+    }
+}
+
+#[cfg(test)]
+fn query(pairs : &[(&str,&str)]) -> HashMap<String,String> {
+    pairs.iter().map( |&(k,v)| (k.to_string(), v.to_string()) ).collect()
+}
+
+#[test]
+fn test_valid() {
+    let q = query(&[("etext_no", "773"), ("limit", "10")]);
+    let mut params = Params::new(&q);
+    assert_eq!(params.required::<usize>("etext_no"), Some(773));
+    assert_eq!(params.bounded("start", 0usize, 0, 1000), 0);
+    assert_eq!(params.bounded("limit", 20usize, 1, 100), 10);
+    assert!(params.finish(true).is_ok());
+}
+
+#[test]
+fn test_all_problems() {
+    let q = query(&[("start", "first"), ("limit", "100000000")]);
+    let mut params = Params::new(&q);
+    assert_eq!(params.required::<usize>("etext_no"), None);
+    params.optional("start", 0usize);
+    params.bounded("limit", 20usize, 1, 100);
+    let problems = params.finish(false).unwrap_err();
+    let names : Vec<&str> = problems.iter().map( |p| &p.parameter[..] ).collect();
+    assert_eq!(names, vec!["etext_no", "start", "limit"]);
+}
+
+#[test]
+fn test_bounded_nan() {
+    let q = query(&[("weight", "NaN")]);
+    let mut params = Params::new(&q);
+    assert_eq!(params.bounded("weight", 1.0, 0.0, 10.0), 1.0);
+    assert!(params.finish(false).is_err());
+}
+
+#[test]
+fn test_strict() {
+    let q = query(&[("etext_no", "773"), ("limt", "10")]);
+    let mut params = Params::new(&q);
+    params.required::<usize>("etext_no");
+    params.bounded("limit", 20usize, 1, 100);
+    assert!(Params::new(&q).finish(false).is_ok());
+    assert_eq!(params.finish(true).unwrap_err(),
+               vec![Problem { parameter : "limt".to_string(), message : "unknown parameter: limt".to_string() }]);
+}
@@ -20,6 +20,10 @@
  * 02110-1301 USA.
  */
 
+use std::cmp::{self,Ordering};
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
 /// An etext number.
 pub type Etext = usize;
 /// Ranking score.
@@ -30,13 +34,179 @@ pub trait Recommendation : Sync {
     /// The vector will be sorted by etext_number.
     fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>>;
 
-    /// Return a vector of (etext number, score) pairs if possible,
-    /// sorted by score.
-    fn sorted_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
-        self.scored_results(etext_no).map( |mut results| {
-            results.sort_by( |&(_,l),&(_,r)| panic_unless!("recommendation results",
-                                                           option: l.partial_cmp(&r)) );
-            results
-        })
+    /// Return the total number of results and the `k` best (etext
+    /// number, score) pairs after skipping the first `offset`, if
+    /// possible. The pairs are sorted by score; equal scores are
+    /// ordered by etext number.
+    fn top_k(&self, etext_no : Etext, k : usize, offset : usize) -> Option<(usize,Vec<(Etext,Score)>)> {
+        self.scored_results(etext_no).map( |results| (results.len(), select_top(&results, k, offset)) )
+    }
+}
+
+/// Results scored in advance, returned whatever the query. This lets
+/// scores for a text outside the data be combined like any others.
+pub struct Scored(pub Vec<(Etext,Score)>);
+
+impl Recommendation for Scored {
+    fn scored_results(&self, _ : Etext) -> Option<Vec<(Etext,Score)>> {
+        Some(self.0.clone())
+    }
+}
+
+/// A result, ordered by score and then by position in the results.
+struct Ranked {
+    score : Score,
+    index : usize,
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other : &Ranked) -> bool { self.cmp(other) == Ordering::Equal }
+}
+
+impl Eq for Ranked { }
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other : &Ranked) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other : &Ranked) -> Ordering {
+        match panic_unless!("recommendation results", option: self.score.partial_cmp(&other.score)) {
+            Ordering::Equal => self.index.cmp(&other.index),
+            ordering        => ordering,
+        }
+    }
+}
+
+/// Select the `k` best (etext number, score) pairs after skipping the
+/// first `offset`, in order of score. Pairs with equal scores remain
+/// in their original order, as they would after a stable sort.
+///
+/// Only `offset + k` pairs are kept, in a heap, rather than sorting
+/// all of the results.
+pub fn select_top(results : &[(Etext,Score)], k : usize, offset : usize) -> Vec<(Etext,Score)> {
+    let n = offset.saturating_add(k);
+    if n == 0 { return Vec::new(); }
+    // A max-heap of the best n results seen so far.
+    let mut heap : BinaryHeap<Ranked> = BinaryHeap::with_capacity(cmp::min(n, results.len()) + 1);
+    for (i, &(_,score)) in results.iter().enumerate() {
+        let ranked = Ranked { score : score, index : i };
+        if heap.len() < n {
+            heap.push(ranked);
+        } else if ranked < *panic_unless!("recommendation heap", option: heap.peek()) {
+            heap.pop();
+            heap.push(ranked);
+        }
+    }
+    heap.into_sorted_vec().into_iter()
+        .skip(offset)
+        .map( |r| results[r.index] )
+        .collect()
+}
+
+/// The position, counted from 1, of `etext_no` among the results in
+/// the order `select_top` gives them, and its score.
+pub fn rank(results : &[(Etext,Score)], etext_no : Etext) -> Option<(usize,Score)> {
+    results.iter().position( |&(e,_)| e == etext_no ).map( |index| {
+        let target = Ranked { score : results[index].1, index : index };
+        let better = results.iter().enumerate()
+            .filter( |&(i,&(_,score))| Ranked { score : score, index : i } < target )
+            .count();
+        (better + 1, target.score)
+    })
+}
+
+/// Agreement of approximate with exact recommendations.
+pub struct Recall {
+    /// Number of etexts compared.
+    pub queries        : usize,
+    /// Mean fraction of the exact best results found.
+    pub recall         : f64,
+    /// Mean time for an exact query, in milliseconds.
+    pub exact_ms       : f64,
+    /// Mean time for an approximate query, in milliseconds.
+    pub approximate_ms : f64,
+}
+
+/// Compare the `k` best results of `approximate` with those of
+/// `exact` for each of the etexts.
+pub fn recall(exact : &Recommendation, approximate : &Recommendation, etexts : &[Etext], k : usize) -> Recall {
+    fn ms(start : Instant) -> f64 {
+        let elapsed = start.elapsed();
+        elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1000000.0
+    }
+    let mut report = Recall { queries : 0, recall : 0.0, exact_ms : 0.0, approximate_ms : 0.0 };
+    for &etext_no in etexts.iter() {
+        let start = Instant::now();
+        let expected = match exact.top_k(etext_no, k, 0) {
+            Some((_, expected)) => expected,
+            None                => continue,
+        };
+        report.exact_ms += ms(start);
+        let start = Instant::now();
+        let found = approximate.top_k(etext_no, k, 0).map( |(_, found)| found ).unwrap_or(Vec::new());
+        report.approximate_ms += ms(start);
+        let matched = expected.iter().filter( |&&(e,_)| found.iter().any( |&(f,_)| f == e ) ).count();
+        report.queries += 1;
+        report.recall  += matched as f64 / cmp::max(1, expected.len()) as f64;
+    }
+    if report.queries > 0 {
+        let queries = report.queries as f64;
+        report.recall         /= queries;
+        report.exact_ms       /= queries;
+        report.approximate_ms /= queries;
+    }
+    report
+}
+
+#[test]
+fn test_select_top() {
+    let results = vec![(1, 0.5), (2, 0.1), (3, 0.9), (4, 0.1), (5, 0.0), (6, 0.7)];
+    assert_eq!(select_top(&results, 3, 0), vec![(5, 0.0), (2, 0.1), (4, 0.1)]);
+    assert_eq!(select_top(&results, 2, 2), vec![(4, 0.1), (1, 0.5)]);
+    assert_eq!(select_top(&results, 10, 4), vec![(6, 0.7), (3, 0.9)]);
+    assert_eq!(select_top(&results, 0, 1), vec![]);
+    assert_eq!(select_top(&results, 3, 10), vec![]);
+}
+
+#[test]
+fn test_select_top_matches_sort() {
+    let results : Vec<(Etext,Score)> = (0..100).map( |i| (i, ((i * 37) % 11) as Score) ).collect();
+    let mut sorted = results.clone();
+    sorted.sort_by( |&(_,l),&(_,r)| l.partial_cmp(&r).unwrap() );
+    for &(k, offset) in [(1, 0), (10, 0), (10, 5), (20, 90), (100, 0)].iter() {
+        let expected : Vec<(Etext,Score)> = sorted.iter().cloned().skip(offset).take(k).collect();
+        assert_eq!(select_top(&results, k, offset), expected);
+    }
+}
+
+#[test]
+fn test_rank() {
+    let results = vec![(1, 0.5), (2, 0.1), (3, 0.9), (4, 0.1), (5, 0.0), (6, 0.7)];
+    assert_eq!(rank(&results, 5), Some((1, 0.0)));
+    assert_eq!(rank(&results, 4), Some((3, 0.1)));
+    assert_eq!(rank(&results, 3), Some((6, 0.9)));
+    assert_eq!(rank(&results, 7), None);
+}
+
+/// A recommendation giving the same scores for every etext it
+/// contains, for tests.
+#[cfg(test)]
+pub struct Fixed(pub Vec<(Etext,Score)>);
+
+#[cfg(test)]
+impl Recommendation for Fixed {
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        if self.0.iter().any( |&(e,_)| e == etext_no ) { Some(self.0.clone()) } else { None }
     }
 }
+
+#[test]
+fn test_recall() {
+    let exact = Fixed(vec![(1, 0.0), (2, 0.1), (3, 0.2), (4, 0.3)]);
+    let approximate = Fixed(vec![(1, 0.0), (3, 0.2), (4, 0.3)]);
+    let report = recall(&exact, &approximate, &[1, 2, 5], 2);
+    assert_eq!(report.queries, 2);
+    // Etext 1 finds half of the best two; etext 2 is not found.
+    assert_eq!(report.recall, 0.25);
+}
@@ -0,0 +1,262 @@
+//! Compressed sets of integers, after Roaring bitmaps.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::cmp::Ordering;
+use std::iter::FromIterator;
+use std::mem;
+
+use nounset::NounSet;
+
+/// Largest number of values stored as an array in a container.
+const ARRAY_MAX : usize = 4096;
+/// Number of 64-bit words in a bitmap container.
+const BITMAP_WORDS : usize = 1024;
+
+/// The values of a container, which all share their upper bits.
+enum Values {
+    /// The low 16 bits of each value, sorted.
+    Array(Vec<u16>),
+    /// A bit for each of the 65536 possible values.
+    Bitmap(Vec<u64>),
+    /// Sorted, disjoint, inclusive ranges of values.
+    Run(Vec<(u16,u16)>),
+}
+
+/// Values from `key << 16` to `(key << 16) + 65535`.
+struct Container {
+    key    : usize,
+    card   : usize,
+    values : Values,
+}
+
+impl Container {
+
+    /// Store the sorted low 16 bits of some values in whichever
+    /// representation is smallest.
+    fn new(key : usize, low : &[u16]) -> Container {
+        let mut runs : Vec<(u16,u16)> = Vec::new();
+        for &v in low.iter() {
+            match runs.last_mut() {
+                Some(&mut (_, ref mut end)) if *end as u32 + 1 == v as u32 => { *end = v; continue; }
+                _ => { }
+            }
+            runs.push((v, v));
+        }
+        let values = if runs.len() * 4 < low.len() * 2 && runs.len() * 4 < BITMAP_WORDS * 8 {
+            Values::Run(runs)
+        } else if low.len() <= ARRAY_MAX {
+            Values::Array(low.to_vec())
+        } else {
+            let mut bitmap = vec![0u64; BITMAP_WORDS];
+            for &v in low.iter() {
+                bitmap[v as usize / 64] |= 1 << (v % 64);
+            }
+            Values::Bitmap(bitmap)
+        };
+        Container { key : key, card : low.len(), values : values }
+    }
+
+    fn contains(&self, v : u16) -> bool {
+        match self.values {
+            Values::Array(ref array)   => array.binary_search(&v).is_ok(),
+            Values::Bitmap(ref bitmap) => bitmap[v as usize / 64] & (1 << (v % 64)) != 0,
+            Values::Run(ref runs)      => {
+                runs.binary_search_by( |&(start,end)| {
+                    if end < v { Ordering::Less } else if start > v { Ordering::Greater } else { Ordering::Equal }
+                }).is_ok()
+            }
+        }
+    }
+
+    fn intersection_cardinality(&self, other : &Container) -> usize {
+        match (&self.values, &other.values) {
+            (&Values::Array(ref a), &Values::Array(ref b)) => {
+                // Merge the sorted arrays.
+                let (mut i, mut j, mut count) = (0, 0, 0);
+                while i < a.len() && j < b.len() {
+                    match a[i].cmp(&b[j]) {
+                        Ordering::Less    => { i += 1; }
+                        Ordering::Greater => { j += 1; }
+                        Ordering::Equal   => { i += 1; j += 1; count += 1; }
+                    }
+                }
+                count
+            }
+            (&Values::Bitmap(ref a), &Values::Bitmap(ref b)) => {
+                a.iter().zip(b.iter()).fold(0, |acc,(x,y)| acc + (x & y).count_ones() as usize)
+            }
+            _ => {
+                // Look up each value of the smaller container in the
+                // larger.
+                let (small, large) = if self.card <= other.card { (self, other) } else { (other, self) };
+                let mut count = 0;
+                small.each( |v| if large.contains(v) { count += 1; } );
+                count
+            }
+        }
+    }
+
+    /// Call `f` with each value, in increasing order.
+    fn each<F : FnMut(u16)>(&self, mut f : F) {
+        match self.values {
+            Values::Array(ref array) => {
+                for &v in array.iter() { f(v); }
+            }
+            Values::Bitmap(ref bitmap) => {
+                for (i, &word) in bitmap.iter().enumerate() {
+                    let mut bits = word;
+                    while bits != 0 {
+                        f((i * 64) as u16 + bits.trailing_zeros() as u16);
+                        bits &= bits - 1;
+                    }
+                }
+            }
+            Values::Run(ref runs) => {
+                for &(start, end) in runs.iter() {
+                    for v in start as u32..end as u32 + 1 { f(v as u16); }
+                }
+            }
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        mem::size_of::<Container>() + match self.values {
+            Values::Array(ref array)   => array.capacity() * 2,
+            Values::Bitmap(ref bitmap) => bitmap.capacity() * 8,
+            Values::Run(ref runs)      => runs.capacity() * 4,
+        }
+    }
+}
+
+/// A compressed set of integers.
+///
+/// Values are grouped by their upper bits into containers of up to
+/// 65536 values, each stored as a sorted array, a bitmap, or a list
+/// of runs, whichever is smallest. A few hundred nouns cost about two
+/// bytes each, however large their numbers.
+pub struct RoaringSet {
+    /// Containers, sorted by key.
+    containers : Vec<Container>,
+}
+
+impl FromIterator<usize> for RoaringSet {
+    fn from_iter<I : IntoIterator<Item=usize>>(iter : I) -> RoaringSet {
+        let mut values : Vec<usize> = iter.into_iter().collect();
+        values.sort();
+        values.dedup();
+        let mut containers = Vec::new();
+        let mut low = Vec::new();
+        let mut i = 0;
+        while i < values.len() {
+            let key = values[i] >> 16;
+            low.clear();
+            while i < values.len() && values[i] >> 16 == key {
+                low.push(values[i] as u16);
+                i += 1;
+            }
+            containers.push(Container::new(key, &low));
+        }
+        containers.shrink_to_fit();
+        RoaringSet { containers : containers }
+    }
+}
+
+impl NounSet for RoaringSet {
+    fn contains(&self, noun : usize) -> bool {
+        match self.containers.binary_search_by( |c| c.key.cmp(&(noun >> 16)) ) {
+            Ok(i)  => self.containers[i].contains(noun as u16),
+            Err(_) => false,
+        }
+    }
+
+    fn cardinality(&self) -> usize {
+        self.containers.iter().fold(0, |acc,c| acc + c.card)
+    }
+
+    fn intersection_cardinality(&self, other : &RoaringSet) -> usize {
+        let (a, b) = (&self.containers, &other.containers);
+        let (mut i, mut j, mut count) = (0, 0, 0);
+        while i < a.len() && j < b.len() {
+            match a[i].key.cmp(&b[j].key) {
+                Ordering::Less    => { i += 1; }
+                Ordering::Greater => { j += 1; }
+                Ordering::Equal   => { count += a[i].intersection_cardinality(&b[j]); i += 1; j += 1; }
+            }
+        }
+        count
+    }
+
+    fn members(&self) -> Vec<usize> {
+        let mut members = Vec::with_capacity(self.cardinality());
+        for c in self.containers.iter() {
+            c.each( |v| members.push(c.key << 16 | v as usize) );
+        }
+        members
+    }
+
+    fn bytes(&self) -> usize {
+        mem::size_of::<RoaringSet>() + self.containers.iter().fold(0, |acc,c| acc + c.bytes())
+    }
+}
+
+#[cfg(test)]
+use mbitset::MBitSet;
+
+#[test]
+fn test_containers() {
+    // An array, a run and a bitmap.
+    let sparse : RoaringSet = vec![3, 70000, 70002, 140000].into_iter().collect();
+    let runs : RoaringSet = (1000..9000).collect();
+    let dense : RoaringSet = (0..30000).filter( |x| x % 3 != 0 ).collect();
+    assert_eq!(sparse.cardinality(), 4);
+    assert_eq!(runs.cardinality(), 8000);
+    assert_eq!(dense.cardinality(), 20000);
+    match (&runs.containers[0].values, &dense.containers[0].values) {
+        (&Values::Run(ref r), &Values::Bitmap(_)) => assert_eq!(r.len(), 1),
+        _                                         => panic!("unexpected container types"),
+    }
+    assert!(sparse.contains(70002) && !sparse.contains(70001) && !sparse.contains(2));
+    assert!(runs.contains(1000) && runs.contains(8999) && !runs.contains(9000));
+    assert!(dense.contains(29999) && !dense.contains(29997));
+    assert_eq!(sparse.members(), vec![3, 70000, 70002, 140000]);
+    assert_eq!(runs.members(), (1000..9000).collect::<Vec<usize>>());
+    assert!(sparse.bytes() < 200);
+}
+
+#[test]
+fn test_matches_mbitset() {
+    let sets : Vec<Vec<usize>> = vec![vec![],
+                                      vec![1, 2, 3, 128, 70000],
+                                      (0..5000).map( |x| x * 7 ).collect(),
+                                      (1000..9000).collect(),
+                                      (0..30000).filter( |x| x % 3 != 0 ).collect(),
+                                      (29000..31265).collect()];
+    for a in sets.iter() {
+        for b in sets.iter() {
+            let (ra, rb) : (RoaringSet, RoaringSet) = (a.iter().cloned().collect(), b.iter().cloned().collect());
+            let (ma, mb) : (MBitSet, MBitSet) = (a.iter().cloned().collect(), b.iter().cloned().collect());
+            assert_eq!(ra.intersection_cardinality(&rb), ma.intersection_cardinality(&mb));
+            assert_eq!(ra.union_cardinality(&rb), ma.union_cardinality(&mb));
+            assert_eq!(ra.jaccard(&rb), ma.jaccard(&mb));
+        }
+    }
+}
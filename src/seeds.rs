@@ -0,0 +1,177 @@
+//! Recommendations from several seed texts at once.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::str::FromStr;
+
+use combination::{Combination,Fusion,Missing,Mode};
+use recommendation::{Etext,Recommendation,Score};
+
+/// Largest number of liked, or of disliked, etexts in a request.
+pub const MAX_SEEDS : usize = 64;
+
+/// Weight of the distance from the disliked etexts, relative to that
+/// from the liked etexts.
+const DISLIKE_WEIGHT : Score = 0.5;
+
+/// Ways of scoring texts against several seeds.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Aggregation {
+    /// Style distance from the mean of the seeds' part-of-speech
+    /// data.
+    Centroid,
+    /// Topic distance from the seeds' nouns, each weighted by the
+    /// fraction of the seeds containing it.
+    Nouns,
+    /// Reciprocal rank fusion of each seed's recommendations.
+    Rank,
+}
+
+impl FromStr for Aggregation {
+    type Err = ();
+
+    fn from_str(s : &str) -> Result<Aggregation,()> {
+        match s {
+            "centroid" => Ok(Aggregation::Centroid),
+            "nouns"    => Ok(Aggregation::Nouns),
+            "rank"     => Ok(Aggregation::Rank),
+            _          => Err(()),
+        }
+    }
+}
+
+/// The body of a multi-seed request.
+///
+/// ```
+/// { "liked" : [773, 1342], "disliked" : [2701] }
+/// ```
+#[derive(RustcDecodable)]
+pub struct Seeds {
+    pub liked    : Vec<Etext>,
+    pub disliked : Option<Vec<Etext>>,
+}
+
+impl Seeds {
+    /// Score every etext other than the seeds, using `score` to
+    /// score the etexts against a group of seeds. Each score is the
+    /// distance from the liked etexts, less a fraction of that from
+    /// the disliked etexts; the result is sorted by etext number.
+    ///
+    /// # Failures
+    ///
+    /// Returns None if `score` cannot score the liked etexts. If it
+    /// cannot score the disliked etexts, they are ignored.
+    pub fn scored_results<F>(&self, score : F) -> Option<Vec<(Etext,Score)>>
+        where F : Fn(&[Etext]) -> Option<Vec<(Etext,Score)>> {
+        let disliked : &[Etext] = self.disliked.as_ref().map( |d| &d[..] ).unwrap_or(&[]);
+        let liked = match score(&self.liked) {
+            Some(liked) => liked,
+            None        => return None,
+        };
+        let results = match if disliked.is_empty() { None } else { score(disliked) } {
+            Some(disliked) => subtract(liked, &disliked),
+            None           => liked,
+        };
+        Some(results.into_iter()
+             .filter( |&(e,_)| !self.liked.contains(&e) && !disliked.contains(&e) )
+             .collect())
+    }
+}
+
+/// Subtract a fraction of the disliked scores from the liked scores.
+/// Both are sorted by etext number.
+fn subtract(liked : Vec<(Etext,Score)>, disliked : &[(Etext,Score)]) -> Vec<(Etext,Score)> {
+    let mut pos = 0;
+    liked.into_iter()
+        .map( |(e,s)| {
+            while pos < disliked.len() && disliked[pos].0 < e { pos += 1; }
+            if pos < disliked.len() && disliked[pos].0 == e { (e, s - DISLIKE_WEIGHT * disliked[pos].1) } else { (e, s) }
+        })
+        .collect()
+}
+
+/// The recommendations for one seed, whatever the query.
+struct Seeded<'a> {
+    recommendation : &'a Recommendation,
+    seed           : Etext,
+}
+
+impl<'a> Recommendation for Seeded<'a> {
+    fn scored_results(&self, _ : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.recommendation.scored_results(self.seed)
+    }
+}
+
+/// Score the etexts by reciprocal rank fusion of the recommendations
+/// from `r` for each seed. Seeds without recommendations are ignored;
+/// an etext missing from some seed's list is ranked last there.
+///
+/// # Panics
+///
+/// There can be at most `MAX_SEEDS` seeds.
+pub fn fused(r : &Recommendation, seeds : &[Etext]) -> Option<Vec<(Etext,Score)>> {
+    let fusion = Fusion {
+        mode     : Mode::ReciprocalRank,
+        missing  : Missing::Worst,
+        fallback : true,
+        ..Fusion::default()
+    };
+    let sources : Vec<Seeded> = seeds.iter().map( |&seed| Seeded { recommendation : r, seed : seed } ).collect();
+    let combination = sources.iter().fold(Combination::new(fusion), |c, source| c.add("seed", source, 1.0));
+    // The query etext is ignored by every source.
+    seeds.first().and_then( |&seed| combination.scored_results(seed) )
+}
+
+/// A recommendation giving a different list of scores for each seed.
+#[cfg(test)]
+struct PerSeed(Vec<(Etext,Vec<(Etext,Score)>)>);
+
+#[cfg(test)]
+impl Recommendation for PerSeed {
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.0.iter().find( |&&(e,_)| e == etext_no ).map( |&(_,ref results)| results.clone() )
+    }
+}
+
+#[test]
+fn test_scored_results() {
+    let seeds = Seeds { liked : vec![1], disliked : Some(vec![2]) };
+    let results = seeds.scored_results( |etexts| {
+        match etexts[0] {
+            1 => Some(vec![(1, 0.0), (2, 0.2), (3, 0.5), (4, 0.5)]),
+            _ => Some(vec![(1, 0.2), (2, 0.0), (3, 0.8), (4, 0.2)]),
+        }
+    }).unwrap();
+    assert_eq!(results, vec![(3, 0.5 - 0.4), (4, 0.5 - 0.1)]);
+    assert!(seeds.scored_results( |_| None ).is_none());
+}
+
+#[test]
+fn test_fused() {
+    let r = PerSeed(vec![(1, vec![(1, 0.0), (2, 0.1), (3, 0.9)]),
+                         (2, vec![(1, 0.1), (2, 0.0), (3, 0.2)])]);
+    let results = fused(&r, &[1, 2, 5]).unwrap();
+    let score = |e : Etext| results.iter().find( |&&(f,_)| f == e ).unwrap().1;
+    // Etexts 1 and 2 rank first and second for one seed each.
+    assert_eq!(score(1), score(2));
+    assert!(score(3) > score(1));
+    assert!(fused(&r, &[5]).is_none());
+}
@@ -23,14 +23,22 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead,BufReader};
-use std::ops::Add;
+use std::cmp::{self,Ordering};
 use std::path::Path;
 
+use distance::{self,Distance,Metric,Mahalanobis};
+use error::{LoadError,parse_field};
+use features::{Normalization,Transform};
 use recommendation::{Etext,Recommendation,Score};
 use matrix::Matrix2;
+use parallel;
+use vptree::VpTree;
 
 type Proportion = f64;
 
+/// First element of the optional header line of a data file.
+const HEADER : &'static str = "etext_no";
+
 /// Part-of-speech / style data
 pub struct Style {
     /// Part-of-speech data, in matrix form.
@@ -39,6 +47,21 @@ pub struct Style {
     etext_to_index : HashMap<Etext,usize>,
     /// Map to convert data row index into an etext number.
     index_to_etext : Vec<Etext>,
+    /// Number of threads used to score the rows.
+    workers        : usize,
+    /// Index for approximate nearest-neighbor searches.
+    tree           : Option<VpTree>,
+    /// Maximum number of rows scored by an approximate search.
+    budget         : usize,
+    /// Distance used unless another is requested.
+    metric         : Metric,
+    /// Mahalanobis distance over the data's covariance.
+    mahalanobis    : Mahalanobis,
+    /// Normalization and weights applied to the data.
+    transform      : Transform,
+    /// Part-of-speech tag of each dimension, if the data file has a
+    /// header.
+    labels         : Option<Vec<String>>,
 }
 
 impl Style {
@@ -56,44 +79,99 @@ impl Style {
     /// Elements on each line should be separated by tabs. The number
     /// of data elements on each line should be equal.
     ///
-    /// # Panics
+    /// The first line may be a header naming the part-of-speech tag
+    /// of each data element, recognized by a first element of
+    /// `etext_no`:
+    ///
+    /// ```
+    /// etext_no tag...
+    /// ```
+    ///
+    /// # Errors
     ///
-    /// This function will die if
+    /// This function will return a `LoadError` if
     ///
-    /// * The data file cannot be read.
+    /// * The data file cannot be read, or is empty.
     ///
     /// * The data file is not composed of tab-separated numbers.
     ///
     /// * The first element of each line is not an integer etext number.
     ///
-    /// * The remaining elements are not floating-point values.
+    /// * The remaining elements are not finite floating-point values.
+    ///
+    /// * A line has a different number of elements than the first.
+    ///
+    /// * The header names a different number of tags than there are
+    ///   data elements.
     ///
     /// # Examples
     ///
     /// ```
-    /// let style : Style = Style::read("gutenberg.pos");
+    /// let style : Style = Style::read("gutenberg.pos").unwrap();
     /// ```
     ///
-    pub fn read<P : AsRef<Path>>(path : P) -> Style {
-        let (etexts, vectors) : (Vec<Etext>,Vec<Vec<Score>>) =
-            BufReader::new( panic_unless!("style data", result: File::open(path)) ).lines()
-            .map( |line| {
-                let line                 = panic_unless!("style_data", result: line);
-                let mut elements         = line.split('\t');
-                // The first element of each line is the etext number.
-                let etext_no: Etext = panic_unless!("etext number",
-                                                    option: elements.nth(0)
-                                                    .and_then(|s| s.parse().ok())
-                                                    );
-                // The remaining elements are part-of-speech data for the etext.
-                let etext_data: Vec<Proportion> = elements
-                    .map( |s| panic_unless!("style data", result: s.parse::<Proportion>()) )
-                    .collect();
-                (etext_no, etext_data)
-            } ).unzip();
-
-        Style {
-            data           : Matrix2::new(&vectors),
+    pub fn read<P : AsRef<Path>>(path : P) -> Result<Style,LoadError> {
+        let path = path.as_ref();
+        let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+        Style::parse(path, BufReader::new(file))
+    }
+
+    /// Construct a Style object from the contents of the data file
+    /// at `path`.
+    fn parse<R : BufRead>(path : &Path, reader : R) -> Result<Style,LoadError> {
+        let mut etexts : Vec<Etext> = Vec::new();
+        let mut vectors : Vec<Vec<Proportion>> = Vec::new();
+        let mut labels : Option<Vec<String>> = None;
+
+        for (i, line) in reader.lines().enumerate() {
+            let lineno           = i + 1;
+            let line             = try!(line.map_err( |e| LoadError::at(path, lineno, None, e) ));
+            let mut elements     = line.split('\t');
+            if lineno == 1 && line.split('\t').next() == Some(HEADER) {
+                labels = Some(elements.skip(1).map( |e| e.to_string() ).collect());
+                continue;
+            }
+            // The first element of each line is the etext number.
+            let etext_no : Etext = try!(parse_field(path, lineno, 1, elements.next(), "etext number"));
+            // The remaining elements are part-of-speech data for the etext.
+            let mut etext_data : Vec<Proportion> = Vec::new();
+            for (j, element) in elements.enumerate() {
+                let proportion : Proportion = try!(parse_field(path, lineno, j + 2, Some(element),
+                                                               "part-of-speech proportion"));
+                if !proportion.is_finite() {
+                    return Err(LoadError::at(path, lineno, Some(j + 2),
+                                             format!("expected part-of-speech proportion, found {:?}", element)));
+                }
+                etext_data.push(proportion);
+            }
+            if let Some(first) = vectors.first() {
+                if first.len() != etext_data.len() {
+                    return Err(LoadError::at(path, lineno, None,
+                                             format!("expected {} proportions, found {}",
+                                                     first.len(), etext_data.len())));
+                }
+            } else if etext_data.is_empty() {
+                return Err(LoadError::at(path, lineno, Some(2), "missing part-of-speech proportions"));
+            }
+            etexts.push(etext_no);
+            vectors.push(etext_data);
+        }
+
+        if vectors.is_empty() {
+            return Err(LoadError::new(path, "no style data"));
+        }
+        if let Some(ref labels) = labels {
+            if labels.len() != vectors[0].len() {
+                return Err(LoadError::at(path, 1, None, format!("expected {} tags, found {}",
+                                                                 vectors[0].len(), labels.len())));
+            }
+        }
+
+        let data = Matrix2::new(&vectors);
+        Ok(Style {
+            mahalanobis    : Mahalanobis::new(&data),
+            transform      : Transform::new(&data, Normalization::None, None),
+            data           : data,
             // Create the mappings from vector index to etext number, and vice versa.
             etext_to_index : etexts.iter()
                 // duplicate etext_nos
@@ -105,8 +183,238 @@ impl Style {
                 // collect into hashmap
                 .collect(),
             index_to_etext : etexts,
+            workers        : 1,
+            tree           : None,
+            budget         : 0,
+            metric         : Metric::Euclidean,
+            labels         : labels,
+        })
+    }
+
+    /// Score the rows using up to `workers` threads.
+    pub fn with_workers(mut self, workers : usize) -> Style {
+        self.workers = workers;
+        self
+    }
+
+    /// The etexts with part-of-speech data.
+    pub fn etexts(&self) -> &[Etext] {
+        &self.index_to_etext
+    }
+
+    /// Normalize and weight the data, replacing the raw proportions.
+    /// This should be done once, before `with_index`.
+    ///
+    /// # Panics
+    ///
+    /// If there are weights, there must be one for each dimension.
+    pub fn with_transform(mut self, normalization : Normalization, weights : Option<Vec<Score>>) -> Style {
+        let transform = Transform::new(&self.data, normalization, weights);
+        self.data = transform.apply_all(&self.data);
+        self.mahalanobis = Mahalanobis::new(&self.data);
+        self.transform = transform;
+        self
+    }
+
+    /// The normalization and weights applied to the data, and to any
+    /// raw vector compared with it.
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    /// The number of part-of-speech proportions for each etext.
+    pub fn dimensions(&self) -> usize {
+        self.transform.columns.len()
+    }
+
+    /// Check that `raw` holds a proportion, between 0 and 1, for each
+    /// dimension, describing the problem if not.
+    pub fn check_vector(&self, raw : &[Proportion]) -> Result<(),String> {
+        if raw.len() != self.dimensions() {
+            return Err(format!("expected {} proportions, found {}", self.dimensions(), raw.len()));
+        }
+        match raw.iter().position( |&x| !(x >= 0.0 && x <= 1.0) ) {
+            Some(i) => Err(format!("proportion {} must be between 0 and 1: {}", i + 1, raw[i])),
+            None    => Ok(()),
+        }
+    }
+
+    /// Use `metric` unless another is requested.
+    pub fn with_metric(mut self, metric : Metric) -> Style {
+        self.metric = metric;
+        self
+    }
+
+    /// The distance used unless another is requested.
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    /// Recommendations using the `metric` distance.
+    pub fn measure(&self, metric : Metric) -> Measured {
+        Measured { style : self, metric : metric }
+    }
+
+    /// Build a vantage-point tree over the rows, so that
+    /// `approximate` can find the nearest neighbors of a text while
+    /// scoring at most `budget` rows. The tree uses the current
+    /// metric, which must obey the triangle inequality.
+    pub fn with_index(mut self, budget : usize) -> Style {
+        let tree = VpTree::new(&self.data, self.distance(self.metric));
+        self.tree = Some(tree);
+        self.budget = budget;
+        self
+    }
+
+    fn distance(&self, metric : Metric) -> &Distance {
+        match metric {
+            Metric::Euclidean   => &distance::Euclidean,
+            Metric::Manhattan   => &distance::Manhattan,
+            Metric::Cosine      => &distance::Cosine,
+            Metric::Chebyshev   => &distance::Chebyshev,
+            Metric::BrayCurtis  => &distance::BrayCurtis,
+            Metric::Mahalanobis => &self.mahalanobis,
         }
     }
+
+    /// The contribution of each dimension to the `metric` distance
+    /// between two etexts, largest first, with the distance; None if
+    /// either etext has no style data.
+    pub fn explain(&self, etext_no : Etext, other : Etext, metric : Metric) -> Option<(Score,Vec<Contribution>)> {
+        let (v1, v2) = match (self.etext_to_index.get(&etext_no), self.etext_to_index.get(&other)) {
+            (Some(&a), Some(&b)) => (&self.data[a], &self.data[b]),
+            _                    => return None,
+        };
+        let distance = self.distance(metric);
+        let mut contributions : Vec<Contribution> = distance.contributions(v1, v2).into_iter()
+            .enumerate()
+            .map( |(i,c)| Contribution {
+                dimension    : i,
+                label        : self.labels.as_ref().map( |labels| &labels[i][..] ),
+                value        : v1[i],
+                other_value  : v2[i],
+                difference   : v2[i] - v1[i],
+                contribution : c,
+            })
+            .collect();
+        contributions.sort_by( |x,y| y.contribution.abs().partial_cmp(&x.contribution.abs()).unwrap_or(Ordering::Equal) );
+        Some((distance.distance(v1, v2), contributions))
+    }
+
+    /// The mean of the data of those etexts which have style data, or
+    /// None if none do.
+    pub fn centroid(&self, etexts : &[Etext]) -> Option<Vec<Score>> {
+        let rows : Vec<&[Proportion]> = etexts.iter()
+            .filter_map( |e| self.etext_to_index.get(e) )
+            .map( |&i| &self.data[i] )
+            .collect();
+        if rows.is_empty() { return None; }
+        let mut centroid = vec![0 as Score; self.dimensions()];
+        for row in rows.iter() {
+            for (c, x) in centroid.iter_mut().zip(row.iter()) { *c += *x; }
+        }
+        for c in centroid.iter_mut() { *c /= rows.len() as Score; }
+        Some(centroid)
+    }
+
+    /// Score every row against a vector of normalized and weighted
+    /// data, such as a `centroid`, using the style's metric.
+    pub fn scored_vector(&self, vector : &[Score]) -> Vec<(Etext,Score)> {
+        self.scan_vector(vector, self.distance(self.metric))
+    }
+
+    /// Score every row against that of the etext, if there is one.
+    fn scan(&self, etext_no : Etext, distance : &Distance) -> Option<Vec<(Etext,Score)>> {
+        self.etext_to_index.get(&etext_no).map( |&idx| self.scan_vector(&self.data[idx], distance) )
+    }
+
+    /// Score every row against `row`.
+    fn scan_vector(&self, row : &[Score], distance : &Distance) -> Vec<(Etext,Score)> {
+        parallel::scan(self.workers, self.index_to_etext.len(), |range| {
+            self.data.rows_range(range.clone())
+                // Compute the distance from row to v.
+                .map( |v| distance.distance(v,row) )
+                // Associated each distance with its index.
+                .zip(range)
+                // Replace the index with the etext number.
+                .map( |(d,i)| (self.index_to_etext[i], d) )
+                // Create the result vector.
+                .collect()
+        })
+    }
+
+    /// Approximate recommendations, if `with_index` has built an
+    /// index.
+    pub fn approximate(&self) -> Option<Approximate> {
+        self.tree.as_ref().map( |tree| Approximate { style : self, tree : tree } )
+    }
+}
+
+/// The part of the distance between two etexts due to one dimension
+/// of the style data.
+#[derive(RustcEncodable)]
+pub struct Contribution<'a> {
+    pub dimension    : usize,
+    /// The part-of-speech tag, if the data file names it.
+    pub label        : Option<&'a str>,
+    /// The etexts' values, after normalization and weighting.
+    pub value        : Score,
+    pub other_value  : Score,
+    /// How much larger the other etext's value is.
+    pub difference   : Score,
+    pub contribution : Score,
+}
+
+/// Style recommendations using a given distance.
+pub struct Measured<'a> {
+    style  : &'a Style,
+    metric : Metric,
+}
+
+impl<'a> Recommendation for Measured<'a> {
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.style.scan(etext_no, self.style.distance(self.metric))
+    }
+}
+
+/// Style recommendations from a vantage-point tree. The best results
+/// are found by scoring a limited number of rows, and so may miss
+/// some of the nearest texts.
+pub struct Approximate<'a> {
+    style : &'a Style,
+    tree  : &'a VpTree,
+}
+
+impl<'a> Recommendation for Approximate<'a> {
+    /// Every result requires scoring every row, so these are the
+    /// exact results.
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.style.scored_results(etext_no)
+    }
+
+    /// Return the approximate best results. The total is the number
+    /// of results an exact query would have.
+    ///
+    /// A page reaching past the budget cannot be found by the search,
+    /// so is found by scanning every row instead.
+    fn top_k(&self, etext_no : Etext, k : usize, offset : usize) -> Option<(usize,Vec<(Etext,Score)>)> {
+        let style = self.style;
+        let n = offset.saturating_add(k);
+        let index = match style.etext_to_index.get(&etext_no) {
+            Some(&index) if n <= style.budget => index,
+            _                                 => return style.top_k(etext_no, k, offset),
+        };
+        let (results, _) = self.tree.search(&style.data, style.distance(style.metric), &style.data[index],
+                                            n, style.budget);
+        if results.len() < cmp::min(n, style.index_to_etext.len()) {
+            return style.top_k(etext_no, k, offset);
+        }
+        let results = results.into_iter()
+            .skip(offset)
+            .map( |(i,d)| (style.index_to_etext[i], d) )
+            .collect();
+        Some((style.index_to_etext.len(), results))
+    }
 }
 
 impl Recommendation for Style {
@@ -129,36 +437,63 @@ impl Recommendation for Style {
     /// `results` will be Some containing a vector of scores compared
     /// with etext number 773, Oscar Wilde's *Lord Arthur Savile's
     /// Crime and Other Stories*.
+    ///
+    /// Scores are distances under the style's metric; see
+    /// `with_metric`.
     fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.scan(etext_no, self.distance(self.metric))
+    }
+}
 
-        let row = match self.etext_to_index.get(&etext_no) {
-            None      => return None,
-            Some(idx) => &self.data[*idx],
-        };
+#[test]
+fn test_header() {
+    let style = Style::parse(Path::new("test.pos"), &b"etext_no\tNN\tVB\n1\t0.5\t0.5\n2\t0.75\t0.25\n"[..]).unwrap();
+    assert_eq!(style.etexts(), &[1, 2]);
+    let (distance, contributions) = style.explain(1, 2, Metric::Manhattan).unwrap();
+    assert_eq!(distance, 0.5);
+    assert_eq!(contributions[0].label, Some("NN"));
+    assert_eq!(contributions[0].difference, 0.25);
+    assert_eq!(contributions[1].difference, -0.25);
+    assert!(style.explain(1, 3, Metric::Manhattan).is_none());
+    let style = Style::parse(Path::new("test.pos"), &b"1\t0.5\t0.5\n"[..]).unwrap();
+    assert!(style.labels.is_none());
+    assert!(Style::parse(Path::new("test.pos"), &b"etext_no\tNN\n1\t0.5\t0.5\n"[..]).is_err());
+    // A damaged first row is not mistaken for a header.
+    let error = Style::parse(Path::new("test.pos"), &b"1x\t0.5\t0.5\n2\t0.75\t0.25\n"[..]).err().unwrap();
+    assert_eq!((error.line, error.column), (Some(1), Some(1)));
+    let error = Style::parse(Path::new("test.pos"), &b"1\t0.5\t0.5\n2\t0.75\tNaN\n"[..]).err().unwrap();
+    assert_eq!((error.line, error.column), (Some(2), Some(3)));
+    assert!(Style::parse(Path::new("test.pos"), &b"1\tinf\t0.5\n"[..]).is_err());
+}
 
-        let x = self.data.rows()
-            // Compute the distance from row to v.
-            .map( |v| distance(v,row) )
-            // Associated each distance with its index.
-            .enumerate()
-            // Replace the index with the etext number.
-            .map( |(i,d)| (self.index_to_etext[i], d) )
-            // Create the result vector.
-            .collect();
+#[test]
+fn test_check_vector() {
+    let style = Style::parse(Path::new("test.pos"), &b"1\t0.5\t0.5\n2\t0.75\t0.25\n"[..]).unwrap();
+    assert!(style.check_vector(&[0.0, 1.0]).is_ok());
+    assert!(style.check_vector(&[0.5]).is_err());
+    assert_eq!(style.check_vector(&[-1.0, 0.5]), Err("proportion 1 must be between 0 and 1: -1".to_string()));
+    assert!(style.check_vector(&[0.5, Proportion::NAN]).is_err());
+    assert!(style.check_vector(&[0.5, Proportion::INFINITY]).is_err());
+}
 
-        Some(x)
-    }
+#[test]
+fn test_centroid() {
+    let style = Style::parse(Path::new("test.pos"), &b"1\t0.5\t0.5\n2\t0.75\t0.25\n3\t1.0\t0.0\n"[..]).unwrap();
+    assert_eq!(style.centroid(&[1, 3, 99]), Some(vec![0.75, 0.25]));
+    assert_eq!(style.centroid(&[99]), None);
+    let results = style.scored_vector(&[0.75, 0.25]);
+    assert_eq!(results[1], (2, 0.0));
 }
 
-/// Compute the Euclidian distance between the two vectors.
-fn distance(v1 : &[Score], v2 : &[Score]) -> Score {
-    assert_eq!(v1.len(), v2.len());
-    let sq = v1.iter()
-        // Match each element with that from the other vector.
-        .zip( v2.iter() )
-        // Compute (elt1 - elt2)^2.
-        .map( |(x,y)| Score::powi(x-y,2) )
-        // Accumulate the value.
-        .fold(0 as Score, Add::add);
-    Score::sqrt(sq)
+#[test]
+fn test_approximate_paging() {
+    let data : String = (0..300).map( |i| format!("{}\t{}\t{}\n", i + 1, i as f64 / 300.0, 1.0 - i as f64 / 300.0) ).collect();
+    let style = Style::parse(Path::new("test.pos"), data.as_bytes()).unwrap().with_index(50);
+    let approximate = style.approximate().unwrap();
+    assert_eq!(approximate.top_k(5, 10, 0), style.top_k(5, 10, 0));
+    // Pages past the budget are found by scanning every row.
+    let (count, results) = approximate.top_k(5, 20, 60).unwrap();
+    assert_eq!((count, results.len()), (300, 20));
+    assert_eq!(Some((count, results)), style.top_k(5, 20, 60));
+    assert_eq!(approximate.top_k(999, 20, 60), None);
 }
@@ -21,22 +21,147 @@
  */
 
 // use std::collections::BitVec;
+use std::cmp;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead,BufReader};
 use std::path::Path;
+use std::str::FromStr;
 
-use recommendation::{Etext,Recommendation,Score};
+use error::{LoadError,parse_field};
+use recommendation::{Etext,Recommendation,Score,select_top};
 use mbitset::MBitSet;
+use minhash::Lsh;
+use nounset::{NounSet,Representation};
+use parallel;
+use roaring::RoaringSet;
+
+/// Noun numbers must be less than this, so that a damaged data file
+/// cannot make the noun sets impossibly large.
+const MAX_NOUN : usize = 1 << 24;
+
+/// The sets of nouns in each text, in the representation chosen
+/// when the data was read.
+enum Rows {
+    Dense(Vec<MBitSet>),
+    Compressed(Vec<RoaringSet>),
+}
+
+impl Rows {
+    fn push(&mut self, nouns : Vec<usize>) {
+        match *self {
+            Rows::Dense(ref mut rows)      => rows.push(nouns.into_iter().collect()),
+            Rows::Compressed(ref mut rows) => rows.push(nouns.into_iter().collect()),
+        }
+    }
+
+    /// The nouns of row i.
+    fn members(&self, i : usize) -> Vec<usize> {
+        match *self {
+            Rows::Dense(ref rows)      => rows[i].members(),
+            Rows::Compressed(ref rows) => rows[i].members(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Rows::Dense(ref rows)      => rows.len(),
+            Rows::Compressed(ref rows) => rows.len(),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        fn bytes<S : NounSet>(rows : &[S]) -> usize {
+            rows.iter().fold(0, |acc,r| acc + r.bytes())
+        }
+        match *self {
+            Rows::Dense(ref rows)      => bytes(rows),
+            Rows::Compressed(ref rows) => bytes(rows),
+        }
+    }
+}
+
+/// Similarity measures between sets of nouns. Scores are one minus
+/// the similarity.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Measure {
+    /// Shared nouns divided by all nouns of either text.
+    Jaccard,
+    /// Sørensen-Dice: shared nouns divided by the mean size of the
+    /// sets.
+    Dice,
+    /// Shared nouns divided by the size of the smaller set.
+    Overlap,
+    /// Cosine of the angle between the sets as binary vectors.
+    Cosine,
+    /// Jaccard, with each noun weighted by its inverse document
+    /// frequency, so that rare nouns count for more.
+    IdfJaccard,
+}
+
+impl Measure {
+    /// The name of the measure, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Measure::Jaccard    => "jaccard",
+            Measure::Dice       => "dice",
+            Measure::Overlap    => "overlap",
+            Measure::Cosine     => "cosine",
+            Measure::IdfJaccard => "idf_jaccard",
+        }
+    }
+}
+
+impl FromStr for Measure {
+    type Err = ();
+
+    fn from_str(s : &str) -> Result<Measure,()> {
+        match s {
+            "jaccard"     => Ok(Measure::Jaccard),
+            "dice"        => Ok(Measure::Dice),
+            "overlap"     => Ok(Measure::Overlap),
+            "cosine"      => Ok(Measure::Cosine),
+            "idf_jaccard" => Ok(Measure::IdfJaccard),
+            _             => Err(()),
+        }
+    }
+}
+
+/// Memory used by the topic data.
+#[derive(RustcEncodable)]
+pub struct Memory {
+    /// Representation of the noun sets: "dense" or "compressed".
+    pub representation : String,
+    /// Number of noun sets.
+    pub texts          : usize,
+    /// Total size of the noun sets, in bytes.
+    pub bytes          : usize,
+}
 
 /// Common nouns-based topic data.
 pub struct Topic {
     /// Vector of the sets of nouns in each text.
-    data : Vec<MBitSet>,
+    data : Rows,
     /// Map to convert etext number to index into data rows.
     pub etext_to_index : HashMap<Etext,usize>,
     /// Map to convert data row index into an etext number.
     pub index_to_etext : Vec<Etext>,
+    /// Number of threads used to score the rows.
+    workers : usize,
+    /// Index for finding candidate recommendations.
+    lsh : Option<Lsh>,
+    /// Similarity measure used unless another is requested.
+    metric : Measure,
+    /// Number of nouns in each text.
+    cardinalities : Vec<usize>,
+    /// Number of texts containing each noun.
+    frequencies : Vec<usize>,
+    /// Inverse document frequency of each noun.
+    idf : Vec<Score>,
+    /// Sum of the inverse document frequencies of each text's nouns.
+    weights : Vec<Score>,
+    /// The noun named by each noun number, if a vocabulary was read.
+    vocabulary : HashMap<usize,String>,
 }
 
 impl Topic {
@@ -49,12 +174,18 @@ impl Topic {
     /// etext_no data
     /// ```
     /// 
-    /// Elements on each line should be separated by tabs.
+    /// Elements on each line should be separated by tabs. The sets
+    /// of nouns are stored as `representation`.
     ///
+    /// The optional vocabulary file names the nouns:
     ///
-    /// # Panics
+    /// ```
+    /// noun_no noun
+    /// ```
+    ///
+    /// # Errors
     ///
-    /// This function will die if
+    /// This function will return a `LoadError` if
     ///
     /// * The data file cannot be read.
     ///
@@ -62,34 +193,72 @@ impl Topic {
     ///
     /// * The first element of each line is not an integer etext number.
     ///
-    /// * The remaining elements are not integer values.
+    /// * The remaining elements are not integer values less than
+    ///   2^24.
+    ///
+    /// * A line of the vocabulary file is not a noun number followed
+    ///   by a noun, or repeats a noun number.
     ///
     /// # Examples
     ///
     /// ```
-    /// let topic : Topic = Topic::read("data/gutenberg.nouns");
+    /// let topic : Topic = Topic::read("data/gutenberg.nouns", Representation::Dense, None).unwrap();
     /// ```
     ///
-    pub fn read<P : AsRef<Path>>(path : P) -> Topic {
-        let (etexts, vectors) : (Vec<Etext>,Vec<MBitSet>) =
-            BufReader::new( panic_unless!("topic data", result: File::open(path)) ).lines()
-            .map( |line| {
-                let line            = panic_unless!("topic data", result: line);
-                let mut elements    = line.split('\t');
-                // The first element of each line is the etext number.
-                let etext_no: Etext = panic_unless!("etext number",
-                                                    option: elements.nth(0)
-                                                    .and_then(|s| s.parse().ok())
-                                                    );
-                // The remaining elements are common-noun bit numbers for the etext.
-                let etext_data = elements
-                    .map( |s| panic_unless!("topic data", result: s.parse::<usize>()) )
-                    .collect();
-                (etext_no, etext_data)
-            } ).unzip();
+    pub fn read<P : AsRef<Path>>(path : P, representation : Representation,
+                                 vocabulary : Option<&Path>) -> Result<Topic,LoadError> {
+        let vocabulary = match vocabulary {
+            Some(vocabulary) => try!(read_vocabulary(vocabulary)),
+            None             => HashMap::new(),
+        };
+        let path = path.as_ref();
+        let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+        let mut etexts : Vec<Etext> = Vec::new();
+        let mut vectors = match representation {
+            Representation::Dense      => Rows::Dense(Vec::new()),
+            Representation::Compressed => Rows::Compressed(Vec::new()),
+        };
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let lineno           = i + 1;
+            let line             = try!(line.map_err( |e| LoadError::at(path, lineno, None, e) ));
+            let mut elements     = line.split('\t');
+            // The first element of each line is the etext number.
+            let etext_no : Etext = try!(parse_field(path, lineno, 1, elements.next(), "etext number"));
+            // The remaining elements are common-noun bit numbers for the etext.
+            let mut etext_data = Vec::new();
+            for (j, element) in elements.enumerate() {
+                etext_data.push( try!(parse_noun(path, lineno, j + 2, Some(element))) );
+            }
+            etexts.push(etext_no);
+            vectors.push(etext_data);
+        }
+
+        let mut topic = Topic::new(etexts, vectors);
+        topic.vocabulary = vocabulary;
+        Ok(topic)
+    }
 
+    /// Construct a Topic object from the etext numbers and their sets
+    /// of nouns, counting the texts containing each noun.
+    fn new(etexts : Vec<Etext>, data : Rows) -> Topic {
+        let n = data.len();
+        let mut document_frequency : Vec<usize> = Vec::new();
+        let mut cardinalities = Vec::with_capacity(n);
+        for i in 0..n {
+            let members = data.members(i);
+            for &noun in members.iter() {
+                if noun >= document_frequency.len() { document_frequency.resize(noun + 1, 0); }
+                document_frequency[noun] += 1;
+            }
+            cardinalities.push(members.len());
+        }
+        let idf : Vec<Score> = document_frequency.iter().map( |&df| inverse_frequency(n, df) ).collect();
+        let weights = (0..n)
+            .map( |i| data.members(i).iter().fold(0 as Score, |acc,&noun| acc + idf[noun]) )
+            .collect();
         Topic {
-            data           : vectors,
+            data           : data,
             etext_to_index : etexts.iter()
                 // duplicate etext_nos
                 .cloned()
@@ -100,8 +269,357 @@ impl Topic {
                 // collect into hashmap
                 .collect(),
             index_to_etext : etexts,
+            workers        : 1,
+            lsh            : None,
+            metric         : Measure::Jaccard,
+            cardinalities  : cardinalities,
+            frequencies    : document_frequency,
+            idf            : idf,
+            weights        : weights,
+            vocabulary     : HashMap::new(),
         }
     }
+
+    /// Score the rows using up to `workers` threads.
+    pub fn with_workers(mut self, workers : usize) -> Topic {
+        self.workers = workers;
+        self
+    }
+
+    /// The etexts with topic data.
+    pub fn etexts(&self) -> &[Etext] {
+        &self.index_to_etext
+    }
+
+    /// Use `metric` unless another is requested.
+    pub fn with_metric(mut self, metric : Measure) -> Topic {
+        self.metric = metric;
+        self
+    }
+
+    /// The similarity measure used unless another is requested.
+    pub fn metric(&self) -> Measure {
+        self.metric
+    }
+
+    /// Recommendations using the `metric` similarity measure.
+    pub fn measure(&self, metric : Measure) -> Measured {
+        Measured { topic : self, metric : metric }
+    }
+
+    /// The nouns found in both etexts, rarest first, or None if either
+    /// etext has no topic data.
+    pub fn shared(&self, etext_no : Etext, other : Etext) -> Option<Vec<SharedNoun>> {
+        let (a, b) = match (self.etext_to_index.get(&etext_no), self.etext_to_index.get(&other)) {
+            (Some(&a), Some(&b)) => (a, b),
+            _                    => return None,
+        };
+        // Members are in increasing order.
+        let nouns = self.data.members(b);
+        let mut shared : Vec<SharedNoun> = self.data.members(a).into_iter()
+            .filter( |noun| nouns.binary_search(noun).is_ok() )
+            .map( |noun| SharedNoun {
+                noun_no : noun,
+                noun    : self.vocabulary.get(&noun).map( |s| &s[..] ),
+                texts   : self.frequencies[noun],
+                idf     : self.idf[noun],
+            })
+            .collect();
+        shared.sort_by( |x,y| (x.texts, x.noun_no).cmp(&(y.texts, y.noun_no)) );
+        Some(shared)
+    }
+
+    /// The number of noun numbers: one more than the largest in the
+    /// data or the vocabulary.
+    pub fn nouns(&self) -> usize {
+        cmp::max(self.frequencies.len(), self.vocabulary.keys().max().map_or(0, |&noun| noun + 1))
+    }
+
+    /// Score every row against a set of nouns which need not be in
+    /// the topic data, using the topic's similarity measure.
+    ///
+    /// # Failures
+    ///
+    /// Returns None if a noun number is not less than `nouns()`; the
+    /// set's size depends on its largest noun number.
+    pub fn scored_nouns(&self, nouns : &[usize]) -> Option<Vec<(Etext,Score)>> {
+        let count = self.nouns();
+        if nouns.iter().any( |&noun| noun >= count ) { return None; }
+        let metric = self.metric;
+        let results = match self.data {
+            Rows::Dense(ref rows)      => {
+                let set : MBitSet = nouns.iter().cloned().collect();
+                parallel::scan(self.workers, rows.len(), |range| self.score(rows, &set, range, metric))
+            }
+            Rows::Compressed(ref rows) => {
+                let set : RoaringSet = nouns.iter().cloned().collect();
+                parallel::scan(self.workers, rows.len(), |range| self.score(rows, &set, range, metric))
+            }
+        };
+        Some(results)
+    }
+
+    /// The nouns of those etexts which have topic data, each with the
+    /// fraction of the etexts containing it, or None if none have.
+    pub fn profile(&self, etexts : &[Etext]) -> Option<Vec<(usize,Score)>> {
+        let rows : Vec<usize> = etexts.iter().filter_map( |e| self.etext_to_index.get(e).cloned() ).collect();
+        if rows.is_empty() { return None; }
+        let mut counts : HashMap<usize,usize> = HashMap::new();
+        for &row in rows.iter() {
+            for noun in self.data.members(row) {
+                *counts.entry(noun).or_insert(0) += 1;
+            }
+        }
+        let mut profile : Vec<(usize,Score)> = counts.into_iter()
+            .map( |(noun,count)| (noun, count as Score / rows.len() as Score) )
+            .collect();
+        profile.sort_by( |x,y| x.0.cmp(&y.0) );
+        Some(profile)
+    }
+
+    /// Score every row against a profile of weighted nouns by
+    /// weighted Jaccard distance, each of the row's nouns having a
+    /// weight of 1. The topic's similarity measure is not used.
+    pub fn scored_profile(&self, profile : &[(usize,Score)]) -> Vec<(Etext,Score)> {
+        let total = profile.iter().fold(0 as Score, |acc,&(_,w)| acc + w);
+        parallel::scan(self.workers, self.data.len(), |range| {
+            match self.data {
+                Rows::Dense(ref rows)      => self.score_profile(rows, profile, total, range),
+                Rows::Compressed(ref rows) => self.score_profile(rows, profile, total, range),
+            }
+        })
+    }
+
+    /// Score the rows at `indices` against a profile whose weights
+    /// sum to `total`.
+    fn score_profile<S : NounSet, I : Iterator<Item=usize>>(&self, rows : &[S], profile : &[(usize,Score)], total : Score,
+                                                            indices : I) -> Vec<(Etext,Score)> {
+        indices
+            .map( |i| {
+                let shared = profile.iter()
+                    .filter( |&&(noun,_)| rows[i].contains(noun) )
+                    .fold(0 as Score, |acc,&(_,w)| acc + w);
+                let union = self.cardinalities[i] as Score + total - shared;
+                (self.index_to_etext[i], if union > 0.0 { 1.0 - shared / union } else { 1.0 })
+            })
+            .collect()
+    }
+
+    /// The inverse document frequency of a noun.
+    fn idf(&self, noun : usize) -> Score {
+        match self.idf.get(noun) {
+            Some(&idf) => idf,
+            None       => inverse_frequency(self.data.len(), 0),
+        }
+    }
+
+    /// Build a locality-sensitive hash index with MinHash signatures
+    /// of `bands * rows` values, so that `approximate` can score only
+    /// the texts likely to be similar. See `Lsh`.
+    pub fn with_lsh(mut self, bands : usize, rows : usize) -> Topic {
+        self.lsh = Some(match self.data {
+            Rows::Dense(ref data)      => Lsh::new(data, bands, rows, self.workers),
+            Rows::Compressed(ref data) => Lsh::new(data, bands, rows, self.workers),
+        });
+        self
+    }
+
+    /// Report the memory used by the noun sets.
+    pub fn memory(&self) -> Memory {
+        let representation = match self.data {
+            Rows::Dense(_)      => "dense",
+            Rows::Compressed(_) => "compressed",
+        };
+        Memory {
+            representation : representation.to_string(),
+            texts          : self.data.len(),
+            bytes          : self.data.bytes(),
+        }
+    }
+
+    /// Score the rows at `indices` against `row`.
+    fn score<S : NounSet, I : Iterator<Item=usize>>(&self, rows : &[S], row : &S, indices : I,
+                                                    metric : Measure) -> Vec<(Etext,Score)> {
+        let scorer = Scorer::new(self, row, metric);
+        indices
+            // translate row numbers to etext_nos.
+            .map(|i| (self.index_to_etext[i], scorer.distance(&rows[i], i)) )
+            .collect()
+    }
+
+    /// Score every row against that of the etext, if there is one.
+    fn scan(&self, etext_no : Etext, metric : Measure) -> Option<Vec<(Etext,Score)>> {
+        let index = match self.etext_to_index.get(&etext_no) {
+            None      => return None,
+            Some(idx) => *idx,
+        };
+
+        let result = parallel::scan(self.workers, self.data.len(), |range| {
+            match self.data {
+                Rows::Dense(ref rows)      => self.score(rows, &rows[index], range, metric),
+                Rows::Compressed(ref rows) => self.score(rows, &rows[index], range, metric),
+            }
+        });
+
+        Some(result)
+    }
+
+    /// Approximate recommendations, if `with_lsh` has built an index.
+    pub fn approximate(&self) -> Option<Approximate> {
+        self.lsh.as_ref().map( |lsh| Approximate { topic : self, lsh : lsh } )
+    }
+}
+
+/// Topic recommendations using a given similarity measure.
+pub struct Measured<'a> {
+    topic  : &'a Topic,
+    metric : Measure,
+}
+
+impl<'a> Recommendation for Measured<'a> {
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.topic.scan(etext_no, self.metric)
+    }
+}
+
+/// Compares sets of nouns with one set, under one measure.
+struct Scorer<'a, S : 'a> {
+    topic       : &'a Topic,
+    metric      : Measure,
+    row         : &'a S,
+    cardinality : usize,
+    /// For IDF-weighted Jaccard, the row's nouns with their weights,
+    /// and their total.
+    terms       : Vec<(usize,Score)>,
+    weight      : Score,
+}
+
+impl<'a, S : NounSet> Scorer<'a, S> {
+    fn new(topic : &'a Topic, row : &'a S, metric : Measure) -> Scorer<'a, S> {
+        let terms : Vec<(usize,Score)> = if metric == Measure::IdfJaccard {
+            row.members().into_iter().map( |noun| (noun, topic.idf(noun)) ).collect()
+        } else {
+            Vec::new()
+        };
+        Scorer {
+            topic       : topic,
+            metric      : metric,
+            row         : row,
+            cardinality : row.cardinality(),
+            weight      : terms.iter().fold(0 as Score, |acc,&(_,w)| acc + w),
+            terms       : terms,
+        }
+    }
+
+    /// The distance to `other`, the set of row `index`.
+    fn distance(&self, other : &S, index : usize) -> Score {
+        let similarity = match self.metric {
+            Measure::Jaccard    => self.row.jaccard(other),
+            Measure::IdfJaccard => {
+                let shared = self.terms.iter()
+                    .filter( |&&(noun,_)| other.contains(noun) )
+                    .fold(0 as Score, |acc,&(_,w)| acc + w);
+                let union = self.weight + self.topic.weights[index] - shared;
+                if union > 0.0 { shared / union } else { 0.0 }
+            }
+            metric              => {
+                let shared = self.row.intersection_cardinality(other) as Score;
+                let (a, b) = (self.cardinality as Score, self.topic.cardinalities[index] as Score);
+                let size = match metric {
+                    Measure::Dice    => (a + b) / 2.0,
+                    Measure::Overlap => Score::min(a, b),
+                    _                => (a * b).sqrt(),
+                };
+                if size > 0.0 { shared / size } else { 0.0 }
+            }
+        };
+        (1f64 - similarity) as Score
+    }
+}
+
+/// A noun shared by two texts.
+#[derive(RustcEncodable)]
+pub struct SharedNoun<'a> {
+    pub noun_no : usize,
+    /// The noun, if the vocabulary names it.
+    pub noun    : Option<&'a str>,
+    /// The number of texts containing the noun.
+    pub texts   : usize,
+    pub idf     : Score,
+}
+
+/// Read a vocabulary file of tab-separated noun numbers and nouns.
+fn read_vocabulary(path : &Path) -> Result<HashMap<usize,String>,LoadError> {
+    let file = try!(File::open(path).map_err( |e| LoadError::new(path, e) ));
+    let mut vocabulary = HashMap::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let lineno       = i + 1;
+        let line         = try!(line.map_err( |e| LoadError::at(path, lineno, None, e) ));
+        let mut elements = line.splitn(2, '\t');
+        let noun_no = try!(parse_noun(path, lineno, 1, elements.next()));
+        let noun = match elements.next() {
+            Some(noun) if !noun.is_empty() => noun.to_string(),
+            _                              => return Err(LoadError::at(path, lineno, Some(2), "missing noun")),
+        };
+        if vocabulary.insert(noun_no, noun).is_some() {
+            return Err(LoadError::at(path, lineno, Some(1), format!("repeated noun number {}", noun_no)));
+        }
+    }
+    Ok(vocabulary)
+}
+
+/// Parse a noun number field, which must be less than `MAX_NOUN`.
+fn parse_noun(path : &Path, line : usize, column : usize, field : Option<&str>) -> Result<usize,LoadError> {
+    let noun_no : usize = try!(parse_field(path, line, column, field, "noun number"));
+    if noun_no >= MAX_NOUN {
+        return Err(LoadError::at(path, line, Some(column), format!("noun number must be less than {}: {}", MAX_NOUN, noun_no)));
+    }
+    Ok(noun_no)
+}
+
+/// Smoothed inverse document frequency of a noun found in `df` of
+/// `n` texts; every noun has a weight of at least 1.
+fn inverse_frequency(n : usize, df : usize) -> Score {
+    ((1 + n) as Score / (1 + df) as Score).ln() + 1.0
+}
+
+/// Topic recommendations from a locality-sensitive hash index. Only
+/// the candidates found by the index are scored, and so some similar
+/// texts may be missed.
+pub struct Approximate<'a> {
+    topic : &'a Topic,
+    lsh   : &'a Lsh,
+}
+
+impl<'a> Recommendation for Approximate<'a> {
+    /// Every result requires scoring every row, so these are the
+    /// exact results.
+    fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.topic.scored_results(etext_no)
+    }
+
+    /// Return the best of the candidate results. The total is the
+    /// number of candidates.
+    ///
+    /// If there are too few candidates to fill the page, every row is
+    /// scanned instead, and the total is the number of results of the
+    /// exact query.
+    fn top_k(&self, etext_no : Etext, k : usize, offset : usize) -> Option<(usize,Vec<(Etext,Score)>)> {
+        let topic = self.topic;
+        let index = match topic.etext_to_index.get(&etext_no) {
+            Some(&index) => index,
+            None         => return None,
+        };
+        let candidates = self.lsh.candidates(index);
+        if candidates.len() < offset.saturating_add(k) && candidates.len() < topic.data.len() {
+            return topic.top_k(etext_no, k, offset);
+        }
+        let results = match topic.data {
+            Rows::Dense(ref rows)      => topic.score(rows, &rows[index], candidates.into_iter(), topic.metric),
+            Rows::Compressed(ref rows) => topic.score(rows, &rows[index], candidates.into_iter(), topic.metric),
+        };
+        Some((results.len(), select_top(&results, k, offset)))
+    }
 }
 
 impl Recommendation for Topic {
@@ -124,32 +642,105 @@ impl Recommendation for Topic {
     /// `results` will be Some containing a vector of scores compared
     /// with etext number 773, Oscar Wilde's *Lord Arthur Savile's
     /// Crime and Other Stories*.
+    ///
+    /// Scores are distances under the topic's similarity measure; see
+    /// `with_metric`.
     fn scored_results(&self, etext_no : Etext) -> Option<Vec<(Etext,Score)>> {
+        self.scan(etext_no, self.metric)
+    }
+}
 
-        let row = match self.etext_to_index.get(&etext_no) {
-            None      => return None,
-            Some(idx) => &self.data[*idx],
-        };
+#[cfg(test)]
+fn topic(rows : &[&[usize]]) -> Topic {
+    let etexts = (1..rows.len() + 1).collect();
+    let mut data = Rows::Dense(Vec::new());
+    for row in rows.iter() { data.push(row.to_vec()); }
+    Topic::new(etexts, data)
+}
 
-        // Randomly large MBitSet to avoid allocations below.
-        let mut intersection = MBitSet::with_capacity(31265);
-        let mut union = MBitSet::with_capacity(31265);
-
-        let result = self.data.iter()
-            .map(|vec| {
-                // Jaccard distance. Inlined to avoid reallocating
-                // intersection and union. There doesn't seem to be a
-                // better way to do this.
-                let intersection_card = intersection.set(row).and(vec).cardinality();
-                let union_card = union.set(row).or(vec).cardinality();
-                1f64 - (intersection_card as f64 / union_card as f64)
-            })
-            // match each row with row number
-            .enumerate()
-            // translate row numbers to etext_nos.
-            .map( |(i,d)| (self.index_to_etext[i], d as Score) )
-            .collect();
+#[test]
+fn test_measures() {
+    let topic = topic(&[&[1, 2, 3, 4], &[3, 4, 5], &[1, 2, 3, 4, 5, 6, 7, 8], &[9]]);
+    let score = |metric : Measure, other : Etext| {
+        let results = topic.measure(metric).scored_results(1).unwrap();
+        results.iter().find( |&&(e,_)| e == other ).unwrap().1
+    };
+    let close = |x : Score, y : Score| (x - y).abs() < 1e-9;
+    assert!(close(score(Measure::Jaccard, 2), 1.0 - 2.0 / 5.0));
+    assert!(close(score(Measure::Dice, 2), 1.0 - 4.0 / 7.0));
+    assert!(close(score(Measure::Overlap, 2), 1.0 - 2.0 / 3.0));
+    assert!(close(score(Measure::Overlap, 3), 0.0));
+    assert!(close(score(Measure::Cosine, 3), 1.0 - 4.0 / 32f64.sqrt()));
+    assert!(close(score(Measure::Jaccard, 4), 1.0));
+    assert!(close(score(Measure::IdfJaccard, 1), 0.0));
+}
 
-        Some(result)
-    }
+#[test]
+fn test_idf_jaccard() {
+    // Texts 1 and 2 share a common noun; texts 1 and 3 share a rare
+    // one. Unweighted, they are equally similar.
+    let topic = topic(&[&[1, 2], &[1, 3], &[2, 4], &[1, 5], &[1, 6]]);
+    let jaccard = topic.measure(Measure::Jaccard).scored_results(1).unwrap();
+    assert_eq!(jaccard[1].1, jaccard[2].1);
+    let idf = topic.measure(Measure::IdfJaccard).scored_results(1).unwrap();
+    assert!(idf[2].1 < idf[1].1);
+}
+
+#[test]
+fn test_shared() {
+    let mut topic = topic(&[&[1, 2, 3, 4], &[2, 3, 4, 5], &[3, 4], &[4]]);
+    topic.vocabulary.insert(2, "anchor".to_string());
+    let shared = topic.shared(1, 2).unwrap();
+    let nouns : Vec<(usize,Option<&str>,usize)> = shared.iter().map( |n| (n.noun_no, n.noun, n.texts) ).collect();
+    assert_eq!(nouns, vec![(2, Some("anchor"), 2), (3, None, 3), (4, None, 4)]);
+    assert!(shared[0].idf > shared[1].idf);
+    assert!(topic.shared(1, 5).is_none());
+}
+
+#[test]
+fn test_profile() {
+    let topic = topic(&[&[1, 2], &[2, 3], &[1, 2, 3], &[4]]);
+    assert_eq!(topic.profile(&[1, 2, 99]), Some(vec![(1, 0.5), (2, 1.0), (3, 0.5)]));
+    assert_eq!(topic.profile(&[99]), None);
+    let results = topic.scored_profile(&topic.profile(&[1, 2]).unwrap());
+    assert_eq!(results, vec![(1, 1.0 - 1.5 / 2.5), (2, 1.0 - 1.5 / 2.5), (3, 1.0 - 2.0 / 3.0), (4, 1.0)]);
+    // A profile with weights of 1 is an ordinary set of nouns.
+    let jaccard = topic.measure(Measure::Jaccard).scored_results(1).unwrap();
+    assert_eq!(topic.scored_profile(&[(1, 1.0), (2, 1.0)]), jaccard);
+}
+
+#[test]
+fn test_scored_nouns() {
+    let topic = topic(&[&[1, 2], &[2, 3], &[1, 2, 3], &[4]]).with_metric(Measure::IdfJaccard);
+    assert_eq!(topic.scored_nouns(&[2, 3]), topic.scored_results(2));
+    // Nouns no text has count only against the matches.
+    let mut topic = topic;
+    topic.vocabulary.insert(9, "zeugma".to_string());
+    assert_eq!(topic.nouns(), 10);
+    let results = topic.scored_nouns(&[4, 9]).unwrap();
+    assert!(results[3].1 > 0.0 && results[3].1 < 1.0);
+    assert!(topic.scored_nouns(&[4, 10]).is_none());
+    assert!(topic.scored_nouns(&[1 << 40]).is_none());
+}
+
+#[test]
+fn test_approximate_paging() {
+    let topic = topic(&[&[1, 2, 3, 4], &[1, 2, 3, 4], &[1, 2, 3, 4],
+                        &[10, 11], &[20, 21], &[30, 31], &[40, 41], &[50, 51]]).with_lsh(4, 2);
+    let approximate = topic.approximate().unwrap();
+    assert_eq!(approximate.top_k(1, 2, 0), Some((3, vec![(1, 0.0), (2, 0.0)])));
+    // Pages past the candidates are found by scanning every row.
+    let (count, results) = approximate.top_k(1, 4, 2).unwrap();
+    assert_eq!((count, results.len()), (8, 4));
+    assert_eq!(Some((count, results)), topic.top_k(1, 4, 2));
+    assert_eq!(approximate.top_k(99, 4, 2), None);
+}
+
+#[test]
+fn test_parse_noun() {
+    let path = Path::new("test.nouns");
+    assert_eq!(parse_noun(path, 1, 2, Some("773")).ok(), Some(773));
+    let e = parse_noun(path, 3, 4, Some("99999999999")).unwrap_err();
+    assert_eq!((e.line, e.column), (Some(3), Some(4)));
+    assert!(parse_noun(path, 1, 2, Some("x")).is_err());
 }
@@ -0,0 +1,216 @@
+//! Vantage-point tree, for approximate nearest-neighbor search.
+
+/*
+ * ashurbanipal.web: Rust Rustful-based interface to Ashurbanipal data
+ * Copyright 2015 Tommy M. McGuire
+ * 
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or (at
+ * your option) any later version.
+ * 
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ * 
+ * You should have received a copy of the GNU General Public License
+ * along with this program; if not, write to the Free Software
+ * Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA
+ * 02110-1301 USA.
+ */
+
+use std::cmp::{self,Ordering};
+use std::collections::BinaryHeap;
+use std::f64;
+
+use distance::Distance;
+use matrix::Matrix2;
+use recommendation::Score;
+
+struct Node {
+    /// Row of the vantage point.
+    point   : usize,
+    /// Median distance from the vantage point to the rows below it.
+    radius  : Score,
+    /// Rows closer to the vantage point than the radius.
+    inside  : Option<usize>,
+    /// Rows at least as far from the vantage point as the radius.
+    outside : Option<usize>,
+}
+
+/// Vantage-point tree over the rows of a matrix.
+///
+/// The tree must be searched with the distance it was built with,
+/// which must obey the triangle inequality. Each node splits the rows
+/// below it into those inside and outside a sphere around its vantage
+/// point, which lets a search skip any subtree that cannot hold a
+/// closer row than those already found.
+pub struct VpTree {
+    nodes : Vec<Node>,
+    root  : Option<usize>,
+}
+
+impl VpTree {
+
+    /// Build a tree over every row of `data`.
+    pub fn new(data : &Matrix2<Score>, distance : &Distance) -> VpTree {
+        let mut tree = VpTree { nodes : Vec::new(), root : None };
+        let mut rows : Vec<usize> = (0..data.rows().count()).collect();
+        tree.root = tree.build(data, distance, &mut rows);
+        tree
+    }
+
+    fn build(&mut self, data : &Matrix2<Score>, distance : &Distance, rows : &mut [usize]) -> Option<usize> {
+        if rows.is_empty() { return None; }
+        // The first row is the vantage point; the rest are split at
+        // the median distance from it.
+        let point = rows[0];
+        let rest = &mut rows[1..];
+        let mut distances : Vec<(Score,usize)> = rest.iter()
+            .map( |&r| (distance.distance(&data[point], &data[r]), r) )
+            .collect();
+        distances.sort_by( |l,r| l.partial_cmp(r).unwrap_or(Ordering::Equal) );
+        for (slot, &(_, r)) in rest.iter_mut().zip(distances.iter()) {
+            *slot = r;
+        }
+        let middle = distances.len() / 2;
+        let radius = distances.get(middle).map( |&(d,_)| d ).unwrap_or(0.0);
+        let (inside, outside) = rest.split_at_mut(middle);
+        let inside = self.build(data, distance, inside);
+        let outside = self.build(data, distance, outside);
+        self.nodes.push(Node { point : point, radius : radius, inside : inside, outside : outside });
+        Some(self.nodes.len() - 1)
+    }
+
+    /// Find the `n` rows nearest `query`, computing at most `budget`
+    /// distances, and return the (row, distance) pairs sorted by
+    /// distance along with the number of distances computed.
+    ///
+    /// Subtrees are searched closest first, so if the budget runs out
+    /// the results are the best found so far. With a budget of at
+    /// least the number of rows, the results are exact.
+    pub fn search(&self, data : &Matrix2<Score>, distance : &Distance, query : &[Score],
+                  n : usize, budget : usize) -> (Vec<(usize,Score)>, usize) {
+        let n = cmp::min(n, self.nodes.len());
+        // The best n rows found, farthest on top.
+        let mut best : BinaryHeap<Entry> = BinaryHeap::with_capacity(n + 1);
+        // Subtrees still to be searched, nearest possible row on top.
+        let mut pending : BinaryHeap<Pending> = BinaryHeap::new();
+        let mut visited = 0;
+        if let Some(root) = self.root {
+            pending.push(Pending { bound : 0.0, node : root });
+        }
+        while let Some(Pending { bound, node }) = pending.pop() {
+            if visited >= budget || n == 0 { break; }
+            if best.len() == n && bound > best.peek().map( |e| e.score ).unwrap_or(f64::INFINITY) {
+                break;
+            }
+            let node = &self.nodes[node];
+            let d = distance.distance(query, &data[node.point]);
+            visited += 1;
+            best.push(Entry { score : d, index : node.point });
+            if best.len() > n { best.pop(); }
+            // By the triangle inequality, no row inside is closer
+            // than d - radius, and no row outside is closer than
+            // radius - d.
+            if let Some(inside) = node.inside {
+                pending.push(Pending { bound : Score::max(bound, d - node.radius), node : inside });
+            }
+            if let Some(outside) = node.outside {
+                pending.push(Pending { bound : Score::max(bound, node.radius - d), node : outside });
+            }
+        }
+        let results = best.into_sorted_vec().into_iter().map( |e| (e.index, e.score) ).collect();
+        (results, visited)
+    }
+}
+
+/// A row, ordered by distance and then by row number.
+struct Entry {
+    score : Score,
+    index : usize,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other : &Entry) -> bool { self.cmp(other) == Ordering::Equal }
+}
+
+impl Eq for Entry { }
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other : &Entry) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other : &Entry) -> Ordering {
+        match self.score.partial_cmp(&other.score).unwrap_or(Ordering::Equal) {
+            Ordering::Equal => self.index.cmp(&other.index),
+            ordering        => ordering,
+        }
+    }
+}
+
+/// A subtree to be searched, ordered so that the subtree with the
+/// smallest bound is the greatest.
+struct Pending {
+    bound : Score,
+    node  : usize,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other : &Pending) -> bool { self.cmp(other) == Ordering::Equal }
+}
+
+impl Eq for Pending { }
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other : &Pending) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other : &Pending) -> Ordering {
+        match other.bound.partial_cmp(&self.bound).unwrap_or(Ordering::Equal) {
+            Ordering::Equal => other.node.cmp(&self.node),
+            ordering        => ordering,
+        }
+    }
+}
+
+#[cfg(test)]
+use distance::Euclidean;
+
+#[cfg(test)]
+fn points() -> Matrix2<Score> {
+    // A scattering of points in the plane.
+    let rows : Vec<Vec<Score>> = (0..200)
+        .map( |i| vec![((i * 37) % 101) as Score / 10.0, ((i * 53) % 89) as Score / 10.0] )
+        .collect();
+    Matrix2::new(&rows)
+}
+
+#[test]
+fn test_exact() {
+    let data = points();
+    let tree = VpTree::new(&data, &Euclidean);
+    for &q in [0, 17, 150].iter() {
+        let mut expected : Vec<(usize,Score)> = data.rows().enumerate()
+            .map( |(i,r)| (i, Euclidean.distance(&data[q], r)) )
+            .collect();
+        expected.sort_by( |l,r| (l.1, l.0).partial_cmp(&(r.1, r.0)).unwrap() );
+        expected.truncate(10);
+        let (results, visited) = tree.search(&data, &Euclidean, &data[q], 10, 200);
+        assert_eq!(results, expected);
+        assert!(visited < 200);
+    }
+}
+
+#[test]
+fn test_budget() {
+    let data = points();
+    let tree = VpTree::new(&data, &Euclidean);
+    let (results, visited) = tree.search(&data, &Euclidean, &data[5], 10, 8);
+    assert_eq!(visited, 8);
+    assert_eq!(results.len(), 8);
+    assert_eq!(tree.search(&data, &Euclidean, &data[5], 0, 200).0, vec![]);
+}
@@ -20,30 +20,134 @@
  * 02110-1301 USA.
  */
 
+use std::cmp;
 use std::error::Error;
-use std::path::Path;
+use std::io::Read;
+use std::path::PathBuf;
+use std::panic::{self,AssertUnwindSafe};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize,Ordering};
 
 use rustful::{Context,Handler,Response,StatusCode};
 
+use rustc_serialize::Encodable;
 use rustc_serialize::json;
 
-use combination::Combination;
+use cache::{Cache,CacheStats,Cached};
+use combination::{Combination,Fusion,Mode};
+use config::Config;
+use distance::Metric;
+use error::LoadError;
+use features;
 use index::Index;
-use metadata::{TextRef,Metadata};
-use recommendation::Recommendation;
-use style::Style;
-use topic::Topic;
+use metadata::{self,Filter,Metadata,Text,TextRef};
+use neighbors::{self,Neighbors,NeighborTables,Settings};
+use params::{Params,Problem};
+use seeds::{self,Aggregation,Seeds};
+use recommendation::{self,Etext,Recall,Recommendation,Score,Scored,select_top};
+use style::{Contribution,Style};
+use topic::{self,SharedNoun,Topic};
 
-pub struct RecState(Style, Topic, Metadata, Index);
+/// Largest weight a request may give a recommendation signal.
+const MAX_WEIGHT : f64 = 1000.0;
+
+/// Largest request body read, in bytes.
+const MAX_BODY : u64 = 64 * 1024;
+
+/// Largest number of nouns in a document to be matched.
+const MAX_NOUNS : usize = 10000;
+
+pub struct RecState(Style, Topic, Metadata, Index, Cache, NeighborTables);
 
 impl RecState {
-    pub fn new<P : AsRef<Path>>(style_path:P, topic_path:P, metadata_path:P) -> RecState {
-        let style = Style::read(style_path);
-        let topic = Topic::read(topic_path);
-        let metadata = Metadata::read(metadata_path);
-        let index    = Index::new(&metadata);
-        RecState( style, topic, metadata, index )
+    /// Load the data files named by the configuration, reporting
+    /// every file that could not be loaded. The neighbor tables are
+    /// loaded if the file exists; otherwise every query is scored when
+    /// it is made. Tables built from other data or settings are an
+    /// error.
+    ///
+    /// # Panics
+    ///
+    /// The configuration must have been validated, so that the data
+    /// file paths are present.
+    pub fn new(config : &Config) -> Result<RecState,Vec<LoadError>> {
+        let data = &config.data;
+        let style_path = panic_unless!("style data path", option: data.style.as_ref());
+        let topic_path = panic_unless!("topic data path", option: data.topic.as_ref());
+        let metadata_path = panic_unless!("metadata path", option: data.metadata.as_ref());
+        match (Style::read(style_path), Topic::read(topic_path, config.topic.sets, data.vocabulary.as_ref().map( |p| p.as_path() )), Metadata::read(metadata_path)) {
+            (Ok(style), Ok(topic), Ok(metadata)) => {
+                let weights  = match data.weights {
+                    Some(ref path) => match features::read_weights(path, style.dimensions()) {
+                        Ok(weights) => Some(weights),
+                        Err(e)      => return Err(vec![e]),
+                    },
+                    None           => None,
+                };
+                let style    = style.with_transform(config.style.normalization, weights);
+                let style    = style.with_workers(config.server.scan_threads).with_metric(config.style.metric);
+                let style    = if config.style.index { style.with_index(config.style.budget) } else { style };
+                let topic    = topic.with_workers(config.server.scan_threads).with_metric(config.topic.metric);
+                let topic    = if config.topic.lsh { topic.with_lsh(config.topic.bands, config.topic.rows) } else { topic };
+                let neighbors = match data.neighbors {
+                    Some(ref path) if path.is_file() => {
+                        match table_settings(config, &style, &topic).and_then( |s| NeighborTables::read(path, &s) ) {
+                            Ok(neighbors) => neighbors,
+                            Err(e)        => return Err(vec![e]),
+                        }
+                    }
+                    _                                => NeighborTables::new(Vec::new()),
+                };
+                let index    = Index::new(&metadata);
+                let cache    = Cache::new(config.cache.capacity, config.cache.bytes);
+                Ok(RecState( style, topic, metadata, index, cache, neighbors ))
+            }
+            (style, topic, metadata) => {
+                let mut errors = Vec::new();
+                if let Err(e) = style { errors.push(e); }
+                if let Err(e) = topic { errors.push(e); }
+                if let Err(e) = metadata { errors.push(e); }
+                Err(errors)
+            }
+        }
+    }
+
+    /// Compare the `k` best approximate and exact recommendations
+    /// for up to `queries` texts, for each index that has been built.
+    pub fn recall(&self, queries : usize, k : usize) -> Vec<(&'static str, Recall)> {
+        let &RecState(ref style, ref topic, _, _, _, _) = self;
+        let mut reports = Vec::new();
+        if let Some(approximate) = style.approximate() {
+            reports.push(("style", recommendation::recall(style, &approximate, &sample(style.etexts(), queries), k)));
+        }
+        if let Some(approximate) = topic.approximate() {
+            reports.push(("topic", recommendation::recall(topic, &approximate, &sample(topic.etexts(), queries), k)));
+        }
+        reports
+    }
+
+    /// Compute the neighbor tables served in place of `/style`,
+    /// `/topic`, and `/combination` with the default settings.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `LoadError` if a data file cannot be read to record
+    /// its fingerprint.
+    pub fn precompute(&self, config : &Config) -> Result<NeighborTables,LoadError> {
+        let &RecState(ref style, ref topic, _, _, _, _) = self;
+        let settings = try!(table_settings(config, style, topic));
+        let n = config.data.neighbor_count;
+        let combination = Combination::new(default_fusion(config))
+            .add("style", style, 1.0)
+            .add("topic", topic, 1.0);
+        let mut etexts : Vec<Etext> = style.etexts().iter().chain(topic.etexts().iter()).cloned().collect();
+        etexts.sort();
+        etexts.dedup();
+        let mut tables = NeighborTables::new(settings);
+        tables.add("style", Neighbors::compute(style, style.etexts(), n));
+        tables.add("topic", Neighbors::compute(topic, topic.etexts(), n));
+        tables.add("combination", Neighbors::compute_combined(&combination, &etexts, n));
+        Ok(tables)
     }
 }
 
@@ -53,130 +157,548 @@ pub enum RecQuery {
     Combination,
     TextLookup,
     TextSearch,
+    Compare,
+    Seeds,
+    Match,
+    Stats,
+    Features,
+    ExplainStyle,
+    ExplainTopic,
 }
 
 impl Handler for RecQuery {
-    fn handle_request(&self, context: Context, response: Response) {
-        let &RecState(ref style, ref topic, _, _)
-            = panic_unless!("recstate", option: context.global.get());
-        match *self {
-            RecQuery::Style       => handle_recommendation_query(style, context, response),
-            RecQuery::Topic       => handle_recommendation_query(topic, context, response),
-            RecQuery::Combination => handle_recommendation_query(&Combination::new(style, topic), context, response),
-            RecQuery::TextLookup  => handle_text_query(context, response),
-            RecQuery::TextSearch  => handle_text_search(context, response),
+    fn handle_request(&self, mut context: Context, mut response: Response) {
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        // A panic inside a handler is reported as an internal error
+        // rather than dropping the connection.
+        let result = panic::catch_unwind(AssertUnwindSafe( || self.dispatch(&mut context) ))
+            .unwrap_or_else( |_| Err(ApiError::internal("internal error while handling request")) );
+        match result {
+            Ok(json) => {
+                response.set_status(StatusCode::Ok);
+                response.send(json);
+            }
+            Err(e) => {
+                response.set_status(e.status);
+                response.send(e.to_json(request_id));
+            }
         }
     }
 }
 
-fn handle_recommendation_query(r : &Recommendation, context: Context, mut response: Response) {
-    let &RecState(_, _, ref metadata, _)
-        = panic_unless!("recstate", option: context.global.get());
-    let start = optional("start", 0, &context);
-    let limit = optional("limit", 20, &context);
-    match required("etext_no", &context) {
-        Some(etext_no) => {
-            match r.sorted_results(etext_no) {
-                Some(rows) => {
-                    let recommendation = Recommendations {
-                        count : rows.len(),
-                        rows  : metadata.add_metadata(&rows, start, limit)
-                    };
-                    match json::encode(&recommendation) {
-                        Ok(json) => {
-                            response.set_status(StatusCode::Ok);
-                            response.send(json);
-                        }
-                        Err(e) => {
-                            response.set_status(StatusCode::InternalServerError);
-                            response.send(e.description());
-                        }
+impl RecQuery {
+    fn dispatch(&self, context: &mut Context) -> Result<String,ApiError> {
+        let &RecState(ref style_data, ref topic_data, _, _, ref cache, ref neighbors)
+            = panic_unless!("recstate", option: context.global.get());
+        let style = Cached::new("style", style_data.metric().name().to_string(), style_data, cache);
+        let topic = Cached::new("topic", topic_data.metric().name().to_string(), topic_data, cache);
+        let mut params = Params::new(&context.query);
+        match *self {
+            // With an index, style and topic queries are approximate
+            // unless the request asks otherwise.
+            RecQuery::Style       => {
+                let metric = style_metric(&mut params, style_data);
+                let exact = params.optional("exact", false);
+                if metric != style_data.metric() {
+                    // Only the configured metric is indexed and precomputed.
+                    let measured = style_data.measure(metric);
+                    let style = Cached::new("style", metric.name().to_string(), &measured, cache);
+                    return handle_recommendation_query(&style, None, params, context);
+                }
+                match style_data.approximate() {
+                    Some(ref approximate) if !exact => {
+                        handle_recommendation_query(approximate, neighbors.get("style"), params, context)
                     }
+                    _ => handle_recommendation_query(&style, neighbors.get("style"), params, context),
+                }
+            }
+            RecQuery::Topic       => {
+                let metric = params.optional("metric", topic_data.metric());
+                let exact = params.optional("exact", false);
+                if metric != topic_data.metric() {
+                    // Only the configured measure is precomputed.
+                    let measured = topic_data.measure(metric);
+                    let topic = Cached::new("topic", metric.name().to_string(), &measured, cache);
+                    return handle_recommendation_query(&topic, None, params, context);
                 }
-                None => {
-                    response.set_status(StatusCode::NotFound);
-                    response.send("no matching etext");
+                match topic_data.approximate() {
+                    Some(ref approximate) if !exact => {
+                        handle_recommendation_query(approximate, neighbors.get("topic"), params, context)
+                    }
+                    _ => handle_recommendation_query(&topic, neighbors.get("topic"), params, context),
                 }
             }
+            RecQuery::Combination => {
+                let combination = combination(&mut params, context, &[("style", &style), ("topic", &topic)]);
+                // Only the default combination was precomputed.
+                let table = if is_default(&combination, context) { neighbors.get("combination") } else { None };
+                handle_combination_query(&combination, table, params, context)
+            }
+            RecQuery::TextLookup  => handle_text_query(context),
+            RecQuery::TextSearch  => handle_text_search(context),
+            RecQuery::Compare     => handle_comparison(&style, &topic, topic_data, params, context),
+            RecQuery::Seeds       => {
+                let body = try!(read_body(&mut context.body));
+                handle_seeds(&style, &topic, &body, params, context)
+            }
+            RecQuery::Match       => {
+                let body = try!(read_body(&mut context.body));
+                handle_match(&body, params, context)
+            }
+            RecQuery::Stats       => handle_stats(params, context),
+            RecQuery::Features    => handle_features(style_data, params, context),
+            RecQuery::ExplainStyle => handle_style_explanation(style_data, params, context),
+            RecQuery::ExplainTopic => handle_topic_explanation(topic_data, params, context),
         }
-        None => {
-            response.set_status(StatusCode::BadRequest);
-            response.send("parameter required: etext_no");
-        }
+    }
+}
+
+/// Answer a recommendation query from the precomputed `table` if it
+/// holds the requested results, or from `r` if not. Filtered queries
+/// score every text, so that the count is that of the filtered
+/// results.
+fn handle_recommendation_query(r : &Recommendation, table : Option<&Neighbors>,
+                               mut params : Params, context: &Context) -> Result<String,ApiError> {
+    let &RecState(_, _, ref metadata, _, _, _)
+        = panic_unless!("recstate", option: context.global.get());
+    let etext_no : Option<Etext> = params.required("etext_no");
+    let (start, limit) = paging(&mut params, context);
+    let filter = filter(&mut params);
+    try!(validate(params, context));
+    // validate() has ensured that etext_no is present.
+    let etext_no = etext_no.unwrap();
+    let found = if filter.is_empty() {
+        precomputed(table, etext_no, limit, start).or_else( || r.top_k(etext_no, limit, start) )
+    } else {
+        r.scored_results(etext_no).map( |results| {
+            let results = metadata.filter(results, &filter);
+            (results.len(), select_top(&results, limit, start))
+        })
     };
+    match found {
+        Some((count, rows)) => {
+            encode(&Recommendations {
+                count : count,
+                rows  : metadata.add_metadata(&rows, 0, limit)
+            })
+        }
+        None => Err(ApiError::not_found("etext_no", format!("no matching etext: {}", etext_no))),
+    }
 }
 
-fn handle_text_query(context: Context, mut response: Response) {
-    let &RecState(_, _, ref metadata, _)
+/// As `handle_recommendation_query`, but noting which sources
+/// contributed to each result. The precomputed table does not record
+/// the contributing sources.
+fn handle_combination_query(combination : &Combination, table : Option<&Neighbors>,
+                            mut params : Params, context: &Context) -> Result<String,ApiError> {
+    let &RecState(_, _, ref metadata, _, _, _)
         = panic_unless!("recstate", option: context.global.get());
-    match required_path("etext_no", &context) {
-        Some(etext_no) => {
-            match metadata.get(etext_no) {
-                Some(text) => {
-                    match json::encode(text) {
-                        Ok(json) => {
-                            response.set_status(StatusCode::Ok);
-                            response.send(json);
-                        }
-                        Err(e) => {
-                            response.set_status(StatusCode::InternalServerError);
-                            response.send(e.description());
-                        }
-                    }
-                }
-                None => {
-                    response.set_status(StatusCode::NotFound);
-                    response.send(format!("no matching etext: {}", etext_no));
-                }
+    let etext_no : Option<Etext> = params.required("etext_no");
+    let (start, limit) = paging(&mut params, context);
+    let filter = filter(&mut params);
+    try!(validate(params, context));
+    // validate() has ensured that etext_no is present.
+    let etext_no = etext_no.unwrap();
+    let stored = table.and_then( |t| if t.covers(limit, start) { t.top_k_contributors(etext_no, limit, start) } else { None } );
+    if let (true, Some((count, rows))) = (filter.is_empty(), stored) {
+        let scores : Vec<(Etext,Score)> = rows.iter().map( |&(e,s,_)| (e,s) ).collect();
+        let mut texts = metadata.add_metadata(&scores, 0, limit);
+        for text in texts.iter_mut() {
+            if let Some(&(_,_,contributors)) = rows.iter().find( |&&(e,_,_)| e == text.etext_no ) {
+                text.signals = Some(combination.names(contributors));
             }
         }
-        None => {
-            response.set_status(StatusCode::BadRequest);
-            response.send("parameter problem: lookup/<etext_no>");
+        return encode(&Recommendations {
+            count : count,
+            rows  : texts,
+        });
+    }
+    match combination.combined_results(etext_no) {
+        Some(results) => {
+            let results : Vec<_> = results.into_iter().filter( |&(e,_,_)| metadata.allows(e, &filter) ).collect();
+            let scores : Vec<(Etext,Score)> = results.iter().map( |&(e,s,_)| (e,s) ).collect();
+            let rows = select_top(&scores, limit, start);
+            let mut texts = metadata.add_metadata(&rows, 0, limit);
+            for text in texts.iter_mut() {
+                // The combined results are sorted by etext number.
+                if let Ok(i) = results.binary_search_by( |&(e,_,_)| e.cmp(&text.etext_no) ) {
+                    text.signals = Some(combination.names(results[i].2));
+                }
+            }
+            encode(&Recommendations {
+                count : results.len(),
+                rows  : texts,
+            })
         }
+        None => Err(ApiError::not_found("etext_no", format!("no matching etext: {}", etext_no))),
     }
 }
 
-fn handle_text_search(context: Context, mut response: Response) {
-    let &RecState(_, _, ref metadata, ref index)
+fn handle_text_query(context: &Context) -> Result<String,ApiError> {
+    let &RecState(_, _, ref metadata, _, _, _)
         = panic_unless!("recstate", option: context.global.get());
-    let start = optional("start", 0, &context);
-    let limit = optional("limit", 20, &context);
-    match required::<String>("query", &context) {
-        Some(query) => {
-            let rows = index.get_entries(&query);
-            let recommendations = Recommendations {
-                count : rows.len(),
-                rows  : metadata.add_metadata(&rows, start, limit),
+    try!(validate(Params::new(&context.query), context));
+    let etext_no = try!(required_path("etext_no", context));
+    match metadata.get(etext_no) {
+        Some(text) => encode(text),
+        None       => Err(ApiError::not_found("etext_no", format!("no matching etext: {}", etext_no))),
+    }
+}
+
+fn handle_text_search(context: &Context) -> Result<String,ApiError> {
+    let &RecState(_, _, ref metadata, ref index, _, _)
+        = panic_unless!("recstate", option: context.global.get());
+    let mut params = Params::new(&context.query);
+    let query : Option<String> = params.required("query");
+    let (start, limit) = paging(&mut params, context);
+    try!(validate(params, context));
+    // validate() has ensured that query is present.
+    let rows = index.get_entries(&query.unwrap());
+    encode(&Recommendations {
+        count : rows.len(),
+        rows  : metadata.add_metadata(&rows, start, limit),
+    })
+}
+
+/// Compare two texts: their scores and ranks in each other's style,
+/// topic, and combined recommendations, and the nouns they share.
+fn handle_comparison(style : &Recommendation, topic : &Recommendation, topic_data : &Topic,
+                     mut params : Params, context : &Context) -> Result<String,ApiError> {
+    let &RecState(_, _, ref metadata, _, _, _)
+        = panic_unless!("recstate", option: context.global.get());
+    let a : Option<Etext> = params.required("a");
+    let b : Option<Etext> = params.required("b");
+    let combination = combination(&mut params, context, &[("style", style), ("topic", topic)]);
+    try!(validate(params, context));
+    // validate() has ensured that a and b are present.
+    let (a, b) = (a.unwrap(), b.unwrap());
+    let (text_a, text_b) = match (metadata.get(a), metadata.get(b)) {
+        (Some(text_a), Some(text_b)) => (text_a, text_b),
+        (None, _)                    => return Err(ApiError::not_found("a", format!("no matching etext: {}", a))),
+        (_, None)                    => return Err(ApiError::not_found("b", format!("no matching etext: {}", b))),
+    };
+    encode(&Comparison {
+        a            : text_a,
+        b            : text_b,
+        style        : compare(style, a, b),
+        topic        : compare(topic, a, b),
+        combination  : compare(&combination, a, b),
+        shared_nouns : topic_data.shared(a, b).map( |shared| shared.len() ),
+    })
+}
+
+/// Score and rank two texts against each other, if `r` has results
+/// for both.
+fn compare(r : &Recommendation, a : Etext, b : Etext) -> Option<Pairing> {
+    let b_in_a = r.scored_results(a).and_then( |results| recommendation::rank(&results, b) );
+    let a_in_b = r.scored_results(b).and_then( |results| recommendation::rank(&results, a) );
+    match (b_in_a, a_in_b) {
+        (Some((b_rank, score)), Some((a_rank, _))) => Some(Pairing { score : score, a_rank : a_rank, b_rank : b_rank }),
+        _                                          => None,
+    }
+}
+
+/// Recommend texts like each of the liked texts, and unlike the
+/// disliked texts, in the body of the request.
+fn handle_seeds(style : &Recommendation, topic : &Recommendation, body : &str,
+                mut params : Params, context : &Context) -> Result<String,ApiError> {
+    let &RecState(ref style_data, ref topic_data, ref metadata, _, _, _)
+        = panic_unless!("recstate", option: context.global.get());
+    let aggregation = params.optional("aggregation", Aggregation::Rank);
+    // Rank aggregation uses the recommendations of a source.
+    let source = if aggregation == Aggregation::Rank { params.optional("source", "combination".to_string()) } else { String::new() };
+    let combination = if source == "combination" { Some(combination(&mut params, context, &[("style", style), ("topic", topic)])) } else { None };
+    if aggregation == Aggregation::Rank && source != "style" && source != "topic" && combination.is_none() {
+        params.problem("source", format!("invalid value for source: {}", source));
+    }
+    let (start, limit) = paging(&mut params, context);
+    try!(validate(params, context));
+    let seeds : Seeds = try!(json::decode(body).map_err( |e| ApiError::bad_request("body", format!("invalid seeds: {}", e)) ));
+    if seeds.liked.is_empty() {
+        return Err(ApiError::bad_request("liked", "at least one liked etext is required"));
+    }
+    if seeds.liked.len() > seeds::MAX_SEEDS {
+        return Err(ApiError::bad_request("liked", format!("at most {} liked etexts are allowed", seeds::MAX_SEEDS)));
+    }
+    if seeds.disliked.as_ref().map_or(false, |d| d.len() > seeds::MAX_SEEDS) {
+        return Err(ApiError::bad_request("disliked", format!("at most {} disliked etexts are allowed", seeds::MAX_SEEDS)));
+    }
+    let results = match aggregation {
+        Aggregation::Centroid => seeds.scored_results( |etexts| style_data.centroid(etexts).map( |c| style_data.scored_vector(&c) ) ),
+        Aggregation::Nouns    => seeds.scored_results( |etexts| topic_data.profile(etexts).map( |p| topic_data.scored_profile(&p) ) ),
+        Aggregation::Rank     => {
+            let r : &Recommendation = match combination {
+                Some(ref combination) => combination,
+                None if source == "style" => style,
+                None                      => topic,
             };
-            match json::encode(&recommendations) {
-                Ok(json) => {
-                    response.set_status(StatusCode::Ok);
-                    response.send(json);
-                }
-                Err(e) => {
-                    response.set_status(StatusCode::InternalServerError);
-                    response.send(e.description());
-                }
-            }
+            seeds.scored_results( |etexts| seeds::fused(r, etexts) )
+        }
+    };
+    match results {
+        Some(results) => {
+            encode(&Recommendations {
+                count : results.len(),
+                rows  : metadata.add_metadata(&select_top(&results, limit, start), 0, limit),
+            })
+        }
+        None => Err(ApiError::not_found("liked", "no data for any liked etext")),
+    }
+}
+
+/// Recommend texts like a document outside the data, given its
+/// part-of-speech proportions, its nouns, or both, in the body of
+/// the request.
+fn handle_match(body : &str, mut params : Params, context : &Context) -> Result<String,ApiError> {
+    let &RecState(ref style, ref topic, ref metadata, _, _, _)
+        = panic_unless!("recstate", option: context.global.get());
+    let document : Document = try!(json::decode(body).map_err( |e| ApiError::bad_request("body", format!("invalid document: {}", e)) ));
+    if document.style.is_none() && document.nouns.is_none() {
+        return Err(ApiError::bad_request("body", "style or nouns is required"));
+    }
+    if let Some(ref raw) = document.style {
+        try!(style.check_vector(raw).map_err( |problem| ApiError::bad_request("style", problem) ));
+    }
+    if let Some(ref nouns) = document.nouns {
+        if nouns.len() > MAX_NOUNS {
+            return Err(ApiError::bad_request("nouns", format!("at most {} nouns are allowed", MAX_NOUNS)));
+        }
+        let count = topic.nouns();
+        if let Some(noun) = nouns.iter().find( |&&noun| noun >= count ) {
+            return Err(ApiError::bad_request("nouns", format!("noun number must be less than {}: {}", count, noun)));
+        }
+    }
+    let style_scores = document.style.as_ref().map( |raw| Scored(style.scored_vector(&style.transform().apply(raw))) );
+    // The noun numbers have been checked.
+    let topic_scores = document.nouns.as_ref()
+        .map( |nouns| Scored(panic_unless!("document nouns", option: topic.scored_nouns(nouns))) );
+    let mut sources : Vec<(&'static str, &Recommendation)> = Vec::new();
+    if let Some(ref scores) = style_scores { sources.push(("style", scores)); }
+    if let Some(ref scores) = topic_scores { sources.push(("topic", scores)); }
+    let combination = combination(&mut params, context, &sources);
+    let (start, limit) = paging(&mut params, context);
+    try!(validate(params, context));
+    // Every source returns the same results for any query etext.
+    let results = panic_unless!("document scores", option: combination.scored_results(0));
+    encode(&Recommendations {
+        count : results.len(),
+        rows  : metadata.add_metadata(&select_top(&results, limit, start), 0, limit),
+    })
+}
+
+fn handle_stats(params : Params, context: &Context) -> Result<String,ApiError> {
+    let &RecState(_, ref topic, _, _, ref cache, _)
+        = panic_unless!("recstate", option: context.global.get());
+    try!(validate(params, context));
+    encode(&Stats {
+        cache : cache.stats(),
+        topic : topic.memory(),
+    })
+}
+
+/// Report the normalization and weights of the style data.
+fn handle_features(style : &Style, params : Params, context: &Context) -> Result<String,ApiError> {
+    try!(validate(params, context));
+    encode(style.transform())
+}
+
+/// Break the style distance between two texts down by dimension,
+/// largest contribution first.
+fn handle_style_explanation(style : &Style, mut params : Params, context : &Context) -> Result<String,ApiError> {
+    let etext_no : Option<Etext> = params.required("etext_no");
+    let other : Option<Etext> = params.required("other");
+    let metric = style_metric(&mut params, style);
+    let (start, limit) = paging(&mut params, context);
+    try!(validate(params, context));
+    // validate() has ensured that etext_no and other are present.
+    let (etext_no, other) = (etext_no.unwrap(), other.unwrap());
+    match style.explain(etext_no, other, metric) {
+        Some((distance, contributions)) => {
+            encode(&StyleExplanation {
+                etext_no   : etext_no,
+                other      : other,
+                metric     : metric.name(),
+                distance   : distance,
+                count      : contributions.len(),
+                dimensions : contributions.into_iter().skip(start).take(limit).collect(),
+            })
+        }
+        None => {
+            let (parameter, missing) = if style.etexts().contains(&etext_no) { ("other", other) } else { ("etext_no", etext_no) };
+            Err(ApiError::not_found(parameter, format!("no style data for etext: {}", missing)))
+        }
+    }
+}
+
+/// List the nouns shared by two texts, rarest first.
+fn handle_topic_explanation(topic : &Topic, mut params : Params, context : &Context) -> Result<String,ApiError> {
+    let etext_no : Option<Etext> = params.required("etext_no");
+    let other : Option<Etext> = params.required("other");
+    let (start, limit) = paging(&mut params, context);
+    try!(validate(params, context));
+    // validate() has ensured that etext_no and other are present.
+    let (etext_no, other) = (etext_no.unwrap(), other.unwrap());
+    match topic.shared(etext_no, other) {
+        Some(shared) => {
+            encode(&SharedNouns {
+                etext_no : etext_no,
+                other    : other,
+                count    : shared.len(),
+                nouns    : shared.into_iter().skip(start).take(limit).collect(),
+            })
         }
         None => {
-            response.set_status(StatusCode::BadRequest);
-            response.send("missing argument: query");
+            let (parameter, missing) = if topic.etext_to_index.contains_key(&etext_no) { ("other", other) } else { ("etext_no", etext_no) };
+            Err(ApiError::not_found(parameter, format!("no topic data for etext: {}", missing)))
         }
     }
 }
 
-fn required<T:FromStr>(v : &str, context : &Context) -> Option<T> {
-    context.query.get(v).and_then( |s| s.parse::<T>().ok() )
+/// Read the body of a request, up to `MAX_BODY` bytes.
+fn read_body<R : Read>(reader : R) -> Result<String,ApiError> {
+    let mut body = String::new();
+    try!(reader.take(MAX_BODY).read_to_string(&mut body)
+         .map_err( |e| ApiError::bad_request("body", format!("could not read request: {}", e)) ));
+    Ok(body)
+}
+
+/// Get the requested window of results, using the configured default
+/// page size and limiting it to the configured maximum.
+fn paging(params : &mut Params, context : &Context) -> (usize, usize) {
+    let config : &Config = panic_unless!("config", option: context.global.get());
+    let start = params.optional("start", 0);
+    let limit = params.bounded("limit", config.query.default_limit, 1, config.query.max_limit);
+    (start, limit)
+}
+
+/// Up to `n` etexts spread evenly through `etexts`.
+fn sample(etexts : &[Etext], n : usize) -> Vec<Etext> {
+    let step = cmp::max(1, etexts.len() / cmp::max(1, n));
+    (0..n).map( |i| i * step ).take_while( |&i| i < etexts.len() ).map( |i| etexts[i] ).collect()
+}
+
+/// The results from a precomputed table, if it has them.
+fn precomputed(table : Option<&Neighbors>, etext_no : Etext, limit : usize, start : usize) -> Option<(usize,Vec<(Etext,Score)>)> {
+    table.and_then( |t| if t.covers(limit, start) { t.top_k(etext_no, limit, start) } else { None } )
+}
+
+/// The metadata conditions of a recommendation query.
+fn filter(params : &mut Params) -> Filter {
+    let mut condition = |name : &str| {
+        let value : String = params.optional(name, String::new());
+        if value.is_empty() { None } else { Some(value) }
+    };
+    let filter = Filter {
+        language         : condition("language"),
+        copyright_status : condition("copyright_status"),
+        loc_class        : condition("loc_class"),
+        released_from    : condition("released_from"),
+        released_to      : condition("released_to"),
+        author           : condition("author"),
+        exclude_author   : condition("exclude_author"),
+    };
+    for &(name, bound) in [("released_from", &filter.released_from), ("released_to", &filter.released_to)].iter() {
+        if let Some(ref date) = *bound {
+            if !metadata::valid_date(date) {
+                params.problem(name, format!("{} must be a date of the form YYYY, YYYY-MM or YYYY-MM-DD: {}", name, date));
+            }
+        }
+    }
+    filter
+}
+
+/// The data and settings that determine the default style, topic,
+/// and combination recommendations, as recorded in neighbor tables.
+fn table_settings(config : &Config, style : &Style, topic : &Topic) -> Result<Settings,LoadError> {
+    let data = &config.data;
+    let file = |path : &Option<PathBuf>| -> Result<String,LoadError> {
+        match *path {
+            Some(ref path) => neighbors::fingerprint(path),
+            None           => Ok("none".to_string()),
+        }
+    };
+    let settings = vec![
+        ("style.data",          try!(file(&data.style))),
+        ("style.texts",         style.etexts().len().to_string()),
+        ("style.normalization", config.style.normalization.name().to_string()),
+        ("style.weights",       try!(file(&data.weights))),
+        ("style.metric",        style.metric().name().to_string()),
+        ("topic.data",          try!(file(&data.topic))),
+        ("topic.texts",         topic.etexts().len().to_string()),
+        ("topic.metric",        topic.metric().name().to_string()),
+        ("combination.fusion",  format!("{:?}", default_fusion(config))),
+    ];
+    Ok(settings.into_iter().map( |(name, value)| (name.to_string(), value) ).collect())
+}
+
+/// Fusion settings of a combination query without fusion parameters.
+fn default_fusion(config : &Config) -> Fusion {
+    Fusion {
+        missing  : config.combination.missing,
+        fallback : config.combination.fallback,
+        ..Fusion::default()
+    }
+}
+
+/// True if a combination has the default settings and weights.
+fn is_default(combination : &Combination, context : &Context) -> bool {
+    let config : &Config = panic_unless!("config", option: context.global.get());
+    *combination.fusion() == default_fusion(config) && combination.sources().iter().all( |s| s.weight == 1.0 )
+}
+
+/// Build a combination of the given sources from the parameters of
+/// a combination query. Each source is weighted by a `<name>_weight`
+/// parameter.
+fn combination<'a>(params : &mut Params, context : &Context,
+                   sources : &[(&'static str, &'a Recommendation)]) -> Combination<'a> {
+    let config : &Config = panic_unless!("config", option: context.global.get());
+    let default = default_fusion(config);
+    let fusion = Fusion {
+        mode          : params.optional("mode", default.mode),
+        normalization : params.optional("normalize", default.normalization),
+        missing       : params.optional("missing", default.missing),
+        fallback      : params.optional("fallback", default.fallback),
+    };
+    let mut combination = Combination::new(fusion);
+    for &(name, recommendation) in sources.iter() {
+        let weight = params.bounded(&format!("{}_weight", name), 1.0, 0.0, MAX_WEIGHT);
+        combination = combination.add(name, recommendation, weight);
+    }
+    if combination.sources().iter().all( |s| s.weight == 0.0 ) {
+        params.problem("mode", "at least one weight must be non-zero".to_string());
+    } else if (fusion.mode == Mode::Min || fusion.mode == Mode::Max) && combination.sources().iter().any( |s| s.weight == 0.0 ) {
+        params.problem("mode", "weights must be non-zero in min and max modes".to_string());
+    }
+    combination
+}
+
+/// The style distance requested by the `metric` parameter. Weighted
+/// data cannot be compared by the Mahalanobis distance, which would
+/// ignore the weights.
+fn style_metric(params : &mut Params, style : &Style) -> Metric {
+    let metric = params.optional("metric", style.metric());
+    if metric == Metric::Mahalanobis && style.transform().is_weighted() {
+        params.problem("metric", "the mahalanobis distance cannot be used with weighted style data".to_string());
+    }
+    metric
+}
+
+/// Check the request's parameters, failing with every problem found.
+fn validate(params : Params, context : &Context) -> Result<(),ApiError> {
+    let config : &Config = panic_unless!("config", option: context.global.get());
+    params.finish(config.query.strict).map_err(ApiError::invalid_parameters)
 }
 
-fn optional<T:FromStr>(v : &str, default : T, context : &Context) -> T {
-    required(v, context).unwrap_or(default)
+fn required_path<T:FromStr>(v: &str, context: &Context) -> Result<T,ApiError> {
+    match context.variables.get(v) {
+        Some(s) => s.parse().map_err( |_| ApiError::bad_request(v, format!("invalid value for {}: {}", v, s)) ),
+        None    => Err(ApiError::bad_request(v, format!("parameter required: {}", v))),
+    }
 }
 
-fn required_path<T:FromStr>(v: &str, context: &Context) -> Option<T> {
-    context.variables.get(v).and_then( |s| s.parse().ok() )
+/// Encode a successful response.
+fn encode<T:Encodable>(value : &T) -> Result<String,ApiError> {
+    json::encode(value).map_err( |e| ApiError::internal(e.description()) )
 }
 
 #[derive(RustcEncodable)]
@@ -184,3 +706,143 @@ struct Recommendations<'a> {
     count : usize,
     rows  : Vec<TextRef<'a>>,
 }
+
+/// The body of a request to match a document outside the data, with
+/// fields shaped like a line of the style and topic data files.
+///
+/// ```
+/// { "style" : [0.12, 0.03, ...], "nouns" : [17, 254, 1003] }
+/// ```
+#[derive(RustcDecodable)]
+struct Document {
+    style : Option<Vec<Score>>,
+    nouns : Option<Vec<usize>>,
+}
+
+#[derive(RustcEncodable)]
+struct Comparison<'a> {
+    a            : &'a Text,
+    b            : &'a Text,
+    style        : Option<Pairing>,
+    topic        : Option<Pairing>,
+    combination  : Option<Pairing>,
+    shared_nouns : Option<usize>,
+}
+
+/// Two texts' standing in one kind of recommendation.
+#[derive(RustcEncodable)]
+struct Pairing {
+    /// The score of b in the recommendations for a.
+    score  : Score,
+    /// The position of a in the recommendations for b, from 1.
+    a_rank : usize,
+    /// The position of b in the recommendations for a, from 1.
+    b_rank : usize,
+}
+
+#[derive(RustcEncodable)]
+struct StyleExplanation<'a> {
+    etext_no   : Etext,
+    other      : Etext,
+    metric     : &'static str,
+    distance   : Score,
+    count      : usize,
+    dimensions : Vec<Contribution<'a>>,
+}
+
+#[derive(RustcEncodable)]
+struct SharedNouns<'a> {
+    etext_no : Etext,
+    other    : Etext,
+    count    : usize,
+    nouns    : Vec<SharedNoun<'a>>,
+}
+
+#[derive(RustcEncodable)]
+struct Stats {
+    cache : CacheStats,
+    topic : topic::Memory,
+}
+
+/// Source of the identifiers included in error responses.
+static NEXT_REQUEST_ID : AtomicUsize = AtomicUsize::new(1);
+
+/// A failed request, sent to the client as an `ErrorResponse`.
+struct ApiError {
+    status    : StatusCode,
+    code      : &'static str,
+    message   : String,
+    parameter : Option<String>,
+    problems  : Vec<Problem>,
+}
+
+impl ApiError {
+    fn bad_request<S : ToString>(parameter : &str, message : S) -> ApiError {
+        ApiError {
+            status    : StatusCode::BadRequest,
+            code      : "bad_request",
+            message   : message.to_string(),
+            parameter : Some(parameter.to_string()),
+            problems  : Vec::new(),
+        }
+    }
+
+    fn not_found<S : ToString>(parameter : &str, message : S) -> ApiError {
+        ApiError {
+            status    : StatusCode::NotFound,
+            code      : "not_found",
+            message   : message.to_string(),
+            parameter : Some(parameter.to_string()),
+            problems  : Vec::new(),
+        }
+    }
+
+    fn internal<S : ToString>(message : S) -> ApiError {
+        ApiError {
+            status    : StatusCode::InternalServerError,
+            code      : "internal_error",
+            message   : message.to_string(),
+            parameter : None,
+            problems  : Vec::new(),
+        }
+    }
+
+    /// Reject a request whose parameters failed validation.
+    fn invalid_parameters(problems : Vec<Problem>) -> ApiError {
+        let (message, parameter) = if problems.len() == 1 {
+            (problems[0].message.clone(), Some(problems[0].parameter.clone()))
+        } else {
+            (format!("{} invalid parameters", problems.len()), None)
+        };
+        ApiError {
+            status    : StatusCode::BadRequest,
+            code      : "invalid_parameters",
+            message   : message,
+            parameter : parameter,
+            problems  : problems,
+        }
+    }
+
+    /// The JSON body describing this error.
+    fn to_json(&self, request_id : usize) -> String {
+        let body = ErrorResponse {
+            code       : self.code,
+            message    : &self.message,
+            parameter  : self.parameter.as_ref().map( |p| &p[..] ),
+            request_id : request_id,
+            problems   : &self.problems,
+        };
+        // Encoding strings and numbers cannot fail.
+        panic_unless!("error response", result: json::encode(&body))
+    }
+}
+
+/// Body of every error response.
+#[derive(RustcEncodable)]
+struct ErrorResponse<'a> {
+    code       : &'a str,
+    message    : &'a str,
+    parameter  : Option<&'a str>,
+    request_id : usize,
+    problems   : &'a [Problem],
+}